@@ -0,0 +1,53 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Context;
+
+use crate::steam::UserSteamId;
+
+/// Steam ids banned by the server operators, on top of VAC and publisher bans
+#[derive(Default)]
+pub struct BanList {
+    steam_ids: HashSet<u64>,
+}
+
+impl BanList {
+    /// Loads a file with one steam id per line. Empty lines and lines starting with # are ignored
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+
+        let mut steam_ids = HashSet::new();
+
+        for (index, line) in file.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let steam_id = line.parse::<u64>().with_context(|| {
+                format!(
+                    "Invalid steam id on line {} of {}",
+                    index + 1,
+                    path.display()
+                )
+            })?;
+            steam_ids.insert(steam_id);
+        }
+
+        Ok(Self { steam_ids })
+    }
+
+    pub fn len(&self) -> usize {
+        self.steam_ids.len()
+    }
+
+    /// Returns the banned id if either the player or the owner of the game is banned.
+    /// Checking the owner stops banned players from coming back through a family shared copy.
+    pub fn find_banned_id(&self, ids: &UserSteamId) -> Option<u64> {
+        [ids.steam_id, ids.owner_steam_id]
+            .iter()
+            .copied()
+            .find(|id| self.steam_ids.contains(id))
+    }
+}
@@ -4,13 +4,15 @@ use tokio::sync::mpsc::{self, error::SendError};
 
 use crate::{math::Vector2, messages::Message, MessageType};
 
-pub const VERSION: u32 = 3;
-
 pub struct Client {
     tx: mpsc::UnboundedSender<MessageType>,
     pub steam_id: u64,
+    /// The steam id of the account that owns the game. Differs from `steam_id` if the game is family shared
+    pub owner_steam_id: u64,
     pub name: String,
     pub position: Vector2,
+    /// The most players the client is matched with at once
+    pub max_peers: usize,
 }
 
 impl PartialEq for Client {
@@ -25,24 +27,40 @@ impl Client {
     pub fn new(
         tx: mpsc::UnboundedSender<MessageType>,
         steam_id: u64,
+        owner_steam_id: u64,
         name: String,
         position: Vector2,
+        max_peers: usize,
     ) -> Self {
         Self {
             tx,
             steam_id,
+            owner_steam_id,
             name,
             position,
+            max_peers,
         }
     }
 
     pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
         self.tx.send(message)
     }
+
+    pub fn is_family_shared(&self) -> bool {
+        self.steam_id != self.owner_steam_id
+    }
 }
 
 impl Display for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({}, {})", &self.name, &self.steam_id)
+        if self.is_family_shared() {
+            write!(
+                f,
+                "({}, {}, shared by {})",
+                &self.name, &self.steam_id, &self.owner_steam_id
+            )
+        } else {
+            write!(f, "({}, {})", &self.name, &self.steam_id)
+        }
     }
 }
@@ -8,13 +8,26 @@ use bincode::{
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::messages::Message;
+use crate::{
+    messages::Message,
+    protocol::{self, ProtocolAdapter},
+};
+
+/// The maximum size of a single frame's payload, excluding the length prefix.
+/// Anything larger is treated as a protocol violation instead of being buffered.
+pub const MAX_FRAME_LENGTH: usize = 4096;
+
+pub type BincodeOptions = WithOtherIntEncoding<
+    WithOtherLimit<WithOtherEndian<DefaultOptions, LittleEndian>, Bounded>,
+    VarintEncoding,
+>;
 
 pub struct MessagesCodec {
-    options: WithOtherIntEncoding<
-        WithOtherLimit<WithOtherEndian<DefaultOptions, LittleEndian>, Bounded>,
-        VarintEncoding,
-    >,
+    options: BincodeOptions,
+    adapter: &'static dyn ProtocolAdapter,
+    protocol_version: u32,
+    /// Set once the handshake has picked a version. Until then the client may be speaking any of them
+    negotiated: bool,
 }
 
 impl MessagesCodec {
@@ -24,7 +37,54 @@ impl MessagesCodec {
                 .with_little_endian()
                 .with_limit(4096)
                 .with_varint_encoding(),
+            adapter: protocol::get_adapter(protocol::VERSION).unwrap(),
+            protocol_version: protocol::VERSION,
+            negotiated: false,
+        }
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Changes the message shapes used for encoding and decoding to the ones of the given protocol version
+    pub fn set_protocol_version(&mut self, version: u32) -> Result<(), anyhow::Error> {
+        match protocol::get_adapter(version) {
+            Some(adapter) => {
+                self.adapter = adapter;
+                self.protocol_version = version;
+                self.negotiated = true;
+                Ok(())
+            }
+            None => anyhow::bail!("Protocol version {} is not supported", version),
+        }
+    }
+
+    /// Serializes a message without the length prefix, for transports that frame messages themselves.
+    /// Returns `None` if the message doesn't exist in the negotiated protocol version.
+    pub fn serialize_payload(&self, message: Message) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.adapter.serialize(self.options, message)
+    }
+
+    /// Deserializes a message without the length prefix, for transports that frame messages themselves
+    pub fn deserialize_payload(&self, payload: &[u8]) -> Result<Message, anyhow::Error> {
+        if self.negotiated {
+            return self.adapter.deserialize(self.options, payload);
         }
+
+        // Bincode rejects trailing bytes, so an older shape can't read a newer message with extra fields at the end.
+        // It only works the other way around because a newer shape runs out of bytes reading an older message,
+        // which makes the newest shape that decodes the one the client used. That's why the newest shapes are tried first
+        let mut last_error = None;
+
+        for adapter in protocol::get_adapters_newest_first() {
+            match adapter.deserialize(self.options, payload) {
+                Ok(message) => return Ok(message),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No protocol adapters available")))
     }
 }
 
@@ -32,7 +92,18 @@ impl Encoder<Message> for MessagesCodec {
     type Error = anyhow::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let payload = self.options.serialize(&item)?;
+        let payload = match self.serialize_payload(item)? {
+            Some(payload) => payload,
+            None => return Ok(()), // The message doesn't exist in the negotiated protocol version
+        };
+
+        if payload.len() > MAX_FRAME_LENGTH {
+            anyhow::bail!(
+                "Message length ({}) > Max frame length ({})",
+                payload.len(),
+                MAX_FRAME_LENGTH
+            );
+        }
 
         crate::encoding::put_varint_le(dst, payload.len() as u64);
         dst.put_slice(&payload);
@@ -46,28 +117,182 @@ impl Decoder for MessagesCodec {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
-        }
+        // Wait for the whole length prefix before consuming anything
+        let (length, header_length) = match crate::encoding::peek_varint_le(src)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
 
-        let length = crate::encoding::get_varint_le(src)? as usize;
-        let remaining = src.remaining();
+        if length == 0 {
+            anyhow::bail!("Message length is zero");
+        }
 
-        if length as usize > remaining {
+        if length > MAX_FRAME_LENGTH as u64 {
             anyhow::bail!(
-                "Message length ({}) > Remaining ({})",
+                "Message length ({}) > Max frame length ({})",
                 length,
-                src.remaining()
+                MAX_FRAME_LENGTH
             );
         }
 
-        if length == 0 {
-            anyhow::bail!("Message length is zero");
+        let frame_length = header_length + length as usize;
+
+        // Wait for the rest of the frame, reserving space for it so the next read can fill it in one go
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
         }
 
-        let message: Self::Item = self.options.deserialize(&src[..length])?;
-        src.advance(length);
+        src.advance(header_length);
+        let payload = src.split_to(length as usize);
+        let message = self.deserialize_payload(&payload)?;
 
         Ok(Some(message))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        math::Vector2,
+        messages::{HandshakeRequest, Ping},
+    };
+
+    fn ping_payload(sequence: u32) -> Vec<u8> {
+        MessagesCodec::new()
+            .serialize_payload(Message::Ping(Ping { sequence }))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn encode(message: Message) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        MessagesCodec::new().encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn assert_ping(message: Option<Message>, expected_sequence: u32) {
+        match message {
+            Some(Message::Ping(Ping { sequence })) => assert_eq!(sequence, expected_sequence),
+            other => panic!("Expected ping {}, got {:?}", expected_sequence, other),
+        }
+    }
+
+    #[test]
+    fn waits_for_whole_frame_fed_byte_by_byte() {
+        let frame = encode(Message::Ping(Ping { sequence: 1234 }));
+        let mut codec = MessagesCodec::new();
+        let mut src = BytesMut::new();
+
+        for (index, byte) in frame.iter().enumerate() {
+            src.put_u8(*byte);
+            let result = codec.decode(&mut src).unwrap();
+
+            if index < frame.len() - 1 {
+                assert!(
+                    result.is_none(),
+                    "Decoded before byte {} arrived",
+                    index + 1
+                );
+            } else {
+                assert_ping(result, 1234);
+            }
+        }
+
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decodes_multi_byte_headers_split_across_reads() {
+        let payload = ping_payload(42);
+
+        let headers: [Vec<u8>; 3] = [
+            [&[251u8][..], &(payload.len() as u16).to_le_bytes()].concat(),
+            [&[252u8][..], &(payload.len() as u32).to_le_bytes()].concat(),
+            [&[253u8][..], &(payload.len() as u64).to_le_bytes()].concat(),
+        ];
+
+        for header in headers.iter() {
+            let frame = [&header[..], &payload[..]].concat();
+
+            // Split inside the header, then between the header and the payload
+            for split in [1, header.len() - 1, header.len()].iter().copied() {
+                let mut codec = MessagesCodec::new();
+                let mut src = BytesMut::from(&frame[..split]);
+
+                assert!(codec.decode(&mut src).unwrap().is_none());
+
+                src.put_slice(&frame[split..]);
+                assert_ping(codec.decode(&mut src).unwrap(), 42);
+                assert!(src.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_frame_before_buffering() {
+        let mut src = BytesMut::new();
+        crate::encoding::put_varint_le(&mut src, MAX_FRAME_LENGTH as u64 + 1);
+        let capacity = src.capacity();
+
+        assert!(MessagesCodec::new().decode(&mut src).is_err());
+        assert_eq!(src.capacity(), capacity);
+    }
+
+    #[test]
+    fn decodes_two_frames_from_one_read() {
+        let mut src = encode(Message::Ping(Ping { sequence: 1 }));
+        src.extend_from_slice(&encode(Message::Ping(Ping { sequence: 2 })));
+
+        let mut codec = MessagesCodec::new();
+        assert_ping(codec.decode(&mut src).unwrap(), 1);
+        assert_ping(codec.decode(&mut src).unwrap(), 2);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    fn encode_handshake(version: u32) -> BytesMut {
+        let mut codec = MessagesCodec::new();
+        codec.set_protocol_version(version).unwrap();
+
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(
+                Message::HandshakeRequest(HandshakeRequest {
+                    auth_session_ticket: vec![1, 2],
+                    matchmaking_password: None,
+                    level_name: "level".to_string(),
+                    position: Vector2 { x: 1.0, y: -2.0 },
+                    version,
+                    max_peers: Some(8),
+                }),
+                &mut buffer,
+            )
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn decodes_handshakes_of_every_version_before_negotiating() {
+        for (version, expected_max_peers) in [(3, None), (4, Some(8))].iter().copied() {
+            let mut src = encode_handshake(version);
+
+            match MessagesCodec::new().decode(&mut src).unwrap() {
+                Some(Message::HandshakeRequest(request)) => {
+                    assert_eq!(request.version, version);
+                    assert_eq!(request.level_name, "level");
+                    assert_eq!(request.max_peers, expected_max_peers);
+                }
+                other => panic!("Expected v{} handshake, got {:?}", version, other),
+            }
+        }
+    }
+
+    #[test]
+    fn older_shape_rejects_newer_handshake() {
+        let mut codec = MessagesCodec::new();
+        codec.set_protocol_version(3).unwrap();
+
+        assert!(codec.decode(&mut encode_handshake(4)).is_err());
+    }
+}
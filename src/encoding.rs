@@ -1,25 +1,42 @@
 // Copied from bincode source
 // https://github.com/bincode-org/bincode/blob/e0ac3245162ba668ba04591897dd88ff5b3096b8/src/config/int.rs
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 
 const SINGLE_BYTE_MAX: u8 = 250;
 const U16_BYTE: u8 = 251;
 const U32_BYTE: u8 = 252;
 const U64_BYTE: u8 = 253;
 
-pub fn get_varint_le(src: &mut BytesMut) -> Result<u64, anyhow::Error> {
-    let discriminant = src.get_u8();
+/// Reads a varint from the start of `src` without consuming it.
+/// Returns the value and the number of bytes it occupies, or `None` if `src` doesn't contain the whole varint yet.
+pub fn peek_varint_le(src: &[u8]) -> Result<Option<(u64, usize)>, anyhow::Error> {
+    let discriminant = match src.first() {
+        Some(discriminant) => *discriminant,
+        None => return Ok(None),
+    };
+
+    let length = match discriminant {
+        0..=SINGLE_BYTE_MAX => 1,
+        U16_BYTE => 3,
+        U32_BYTE => 5,
+        U64_BYTE => 9,
+        _ => anyhow::bail!("Invalid discriminant = {}", discriminant),
+    };
+
+    if src.len() < length {
+        return Ok(None);
+    }
+
+    let mut bytes = [0u8; 8];
+    bytes[..length - 1].copy_from_slice(&src[1..length]);
 
     let out = match discriminant {
         byte @ 0..=SINGLE_BYTE_MAX => byte as u64,
-        U16_BYTE => src.get_u16_le() as u64,
-        U32_BYTE => src.get_u32_le() as u64,
-        U64_BYTE => src.get_u64_le() as u64,
-        _ => anyhow::bail!("Invalid discriminant = {}", discriminant),
+        _ => u64::from_le_bytes(bytes),
     };
 
-    Ok(out)
+    Ok(Some((out, length)))
 }
 
 pub fn put_varint_le(src: &mut BytesMut, val: u64) {
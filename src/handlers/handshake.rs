@@ -1,65 +1,248 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use anyhow::Context;
 use futures::SinkExt;
-use tokio::{
-    net::TcpStream,
-    sync::{mpsc, Mutex},
-};
-use tokio_util::codec::Framed;
+use tokio::sync::{mpsc, Mutex};
 
 use crate::{
     chat::ChatChannel,
-    client::{self, Client},
-    codec::MessagesCodec,
+    client::Client,
     messages::{
-        HandshakeRequest, HandshakeResponse, Message, OutgoingChatMessage, ServerStatusUpdate,
+        ErrorCode, HandshakeRequest, HandshakeResponse, Kicked, Message, OutgoingChatMessage,
+        ServerStatusUpdate,
     },
+    options::{BanPolicy, DuplicateLoginPolicy, LaunchOptions},
+    protocol,
     state::{MatchmakingOptions, State},
-    steam,
+    steam::{SteamAuthError, SteamUnavailable},
+    transport::Transport,
 };
 
 pub async fn handle_message(
     message: &HandshakeRequest,
     tx: mpsc::UnboundedSender<Message>,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
+    options: &LaunchOptions,
 ) -> Result<(), anyhow::Error> {
-    if message.version != client::VERSION {
-        send_response(
+    // Clients report the newest version they speak, so talk to them in that version unless ours is older
+    let negotiated_version = message.version.min(options.max_protocol_version);
+    let min_supported_version = options.min_protocol_version;
+
+    // Reply in the newest shape the client knows, even past our maximum, so it can read the negotiated version.
+    // Clients that are too old get the oldest shape we know, which is the best bet for them to understand it
+    messages.set_protocol_version(
+        message
+            .version
+            .clamp(protocol::OLDEST_VERSION, protocol::VERSION),
+    )?;
+
+    if negotiated_version < min_supported_version {
+        send_rejection(
             messages,
-            HandshakeResponse {
-                success: false,
-                error_message: Some("Your version is outdated".to_string()),
-            },
+            ErrorCode::OutdatedVersion,
+            format!(
+                "Your version is outdated. Please update the mod to protocol version {} or newer.",
+                min_supported_version
+            ),
+            negotiated_version,
+            min_supported_version,
         )
         .await?;
-        anyhow::bail!("Client version {} mismatch", message.version);
+        anyhow::bail!(
+            "Client version {} is older than minimum supported version {}",
+            message.version,
+            min_supported_version
+        );
     }
 
-    match steam::verify_user_auth_ticket(&message.auth_session_ticket).await {
+    // Don't hold the lock while waiting for steam
+    let steam = state.lock().await.steam();
+
+    match steam
+        .verify_user_auth_ticket(&message.auth_session_ticket)
+        .await
+    {
         Ok(ids) => {
-            let user_infos = steam::get_player_summaries(vec![ids.steam_id]).await?;
-            let user_info = user_infos
-                .get(&ids.steam_id)
-                .context("Could not get user info from steam")?;
+            let first_address = state
+                .lock()
+                .await
+                .used_tickets_mut()
+                .record(&message.auth_session_ticket, source);
+
+            if let Some(first_address) = first_address {
+                tracing::warn!(
+                    target: "security",
+                    steam_id = ids.steam_id,
+                    %first_address,
+                    "Auth ticket reused from {}",
+                    source
+                );
+
+                send_rejection(
+                    messages,
+                    ErrorCode::TicketReused,
+                    "This auth ticket has already been used. Please restart the game.".to_string(),
+                    negotiated_version,
+                    min_supported_version,
+                )
+                .await?;
+                anyhow::bail!("Rejected reused auth ticket for {}", ids);
+            }
+
+            if let Some(banned_id) = state.lock().await.ban_list().find_banned_id(&ids) {
+                tracing::warn!(
+                    target: "moderation",
+                    steam_id = ids.steam_id,
+                    owner_steam_id = ids.owner_steam_id,
+                    banned_id,
+                    "Player on the ban list connecting from {}",
+                    source
+                );
+
+                send_rejection(
+                    messages,
+                    ErrorCode::Banned,
+                    "You are banned from this server.".to_string(),
+                    negotiated_version,
+                    min_supported_version,
+                )
+                .await?;
+                anyhow::bail!("Rejected player {} on the ban list", ids);
+            }
+
+            let ban_policy = if ids.vac_banned || ids.publisher_banned {
+                tracing::warn!(
+                    target: "moderation",
+                    steam_id = ids.steam_id,
+                    owner_steam_id = ids.owner_steam_id,
+                    vac_banned = ids.vac_banned,
+                    publisher_banned = ids.publisher_banned,
+                    policy = ?options.ban_policy,
+                    "Banned player connecting from {}",
+                    source
+                );
+                options.ban_policy
+            } else {
+                BanPolicy::Allow
+            };
+
+            if ban_policy == BanPolicy::Reject {
+                send_rejection(
+                    messages,
+                    ErrorCode::Banned,
+                    "Your account has a VAC or game ban and can not play online.".to_string(),
+                    negotiated_version,
+                    min_supported_version,
+                )
+                .await?;
+                anyhow::bail!("Rejected banned player {}", ids);
+            }
+
+            let user_info = match steam.get_player_summaries(vec![ids.steam_id]).await {
+                Ok(mut user_infos) => user_infos.remove(&ids.steam_id),
+                Err(error) => {
+                    let (error_message, error_code) = steam_error_response(&error);
+                    send_rejection(
+                        messages,
+                        error_code,
+                        error_message,
+                        negotiated_version,
+                        min_supported_version,
+                    )
+                    .await?;
+                    return Err(error.context("Could not get user info from steam"));
+                }
+            };
+
+            let user_info = match user_info {
+                Some(user_info) => user_info,
+                None => {
+                    send_rejection(
+                        messages,
+                        ErrorCode::Unknown,
+                        "Steam did not return your profile. Please try again.".to_string(),
+                        negotiated_version,
+                        min_supported_version,
+                    )
+                    .await?;
+                    anyhow::bail!("Could not get user info from steam for {}", ids);
+                }
+            };
 
             let name = &user_info.name;
 
             let mut state = state.lock().await;
-            let client = Client::new(tx, ids.steam_id, name.clone(), message.position);
-            tracing::info!("{} connected", client);
 
-            let matchmaking_options = MatchmakingOptions::new(
+            if let Some(existing_address) = state.find_client_by_steam_id(ids.steam_id) {
+                match options.duplicate_login_policy {
+                    DuplicateLoginPolicy::KickOld => {
+                        if let Some(existing) = state.remove_client(&existing_address) {
+                            tracing::info!(
+                                "{} connected again from {}, kicking the session at {}",
+                                existing,
+                                source,
+                                existing_address
+                            );
+
+                            // The old connection closes once it has delivered this. Ignore failed sends, it may be gone already
+                            let _ = existing.send(Message::Kicked(Kicked {
+                                reason: "You connected from another location.".to_string(),
+                            }));
+                        }
+                    }
+                    DuplicateLoginPolicy::RejectNew => {
+                        send_rejection(
+                            messages,
+                            ErrorCode::AlreadyConnected,
+                            "You are already connected from another location.".to_string(),
+                            negotiated_version,
+                            min_supported_version,
+                        )
+                        .await?;
+                        anyhow::bail!(
+                            "Rejected {} already connected from {}",
+                            ids,
+                            existing_address
+                        );
+                    }
+                }
+            }
+
+            let max_peers = message.max_peers.map_or(options.max_peers, |max_peers| {
+                max_peers.min(options.max_peers)
+            });
+
+            let client = Client::new(
+                tx,
+                ids.steam_id,
+                ids.owner_steam_id,
+                name.clone(),
+                message.position,
+                max_peers as usize,
+            );
+            tracing::info!(
+                "{} connected using protocol version {}",
+                client,
+                negotiated_version
+            );
+
+            let mut matchmaking_options = MatchmakingOptions::new(
                 message.matchmaking_password.clone(),
                 message.level_name.clone(),
             );
 
+            if ban_policy == BanPolicy::Solo {
+                matchmaking_options = matchmaking_options.isolated(ids.steam_id);
+            }
+
             let clients_total = state.get_clients_iter().len();
             let clients_in_group = state.get_clients_in_group(&matchmaking_options).count();
 
             let welcome_message = match (clients_total, clients_in_group) {
+                _ if ban_policy == BanPolicy::Solo => {
+                    "Welcome! Your account has a VAC or game ban, so you will not be matched with other players.".into()
+                }
                 (0, 0) => "Welcome! There are currently no other players online.".into(),
                 (total, 0) => format!(
                     "Welcome! There are {} other players online, but none of them in your group.",
@@ -78,10 +261,15 @@ pub async fn handle_message(
                 HandshakeResponse {
                     success: true,
                     error_message: None,
+                    negotiated_version,
+                    min_supported_version,
+                    error_code: None,
                 },
             )
             .await?;
 
+            messages.set_protocol_version(negotiated_version)?;
+
             messages
                 .send(Message::OutgoingChatMessage(OutgoingChatMessage {
                     channel: ChatChannel::Global,
@@ -95,34 +283,207 @@ pub async fn handle_message(
                 .send(Message::ServerStatusUpdate(ServerStatusUpdate {
                     total_players: state.get_clients_iter().len() as u32,
                     group_players: state
-                        .get_clients_in_group(state.get_matchmaking_options(source))
-                        .count() as u32,
+                        .get_matchmaking_options(source)
+                        .map_or(0, |options| state.get_clients_in_group(options).count())
+                        as u32,
                 }))
                 .await?;
         }
         Err(error) => {
-            tracing::info!("{} failed to auth: {}", source, error);
+            tracing::info!("{} failed to auth: {:#}", source, error);
 
-            send_response(
+            let (error_message, error_code) = steam_error_response(&error);
+            send_rejection(
                 messages,
-                HandshakeResponse {
-                    success: false,
-                    error_message: Some(
-                        "An unexpected error occured when handling handshake request".to_string(),
-                    ),
-                },
+                error_code,
+                error_message,
+                negotiated_version,
+                min_supported_version,
             )
             .await?;
+            // The client is not allowed to stay connected without a verified steam account
+            return Err(error.context("Failed to verify auth ticket"));
         }
     }
 
     Ok(())
 }
 
+/// Explains a failed steam call to the player
+fn steam_error_response(error: &anyhow::Error) -> (String, ErrorCode) {
+    if let Some(unavailable) = error.downcast_ref::<SteamUnavailable>() {
+        (
+            format!(
+                "Steam is not responding right now. Please try again in {} seconds.",
+                unavailable.retry_after.as_secs()
+            ),
+            ErrorCode::SteamUnavailable,
+        )
+    } else if let Some(auth_error) = error.downcast_ref::<SteamAuthError>() {
+        auth_error_response(*auth_error)
+    } else {
+        (
+            "An unexpected error occured when handling handshake request".to_string(),
+            ErrorCode::Unknown,
+        )
+    }
+}
+
+/// Tells the player whether getting a new ticket helps or if they have to wait for the server to be fixed
+fn auth_error_response(error: SteamAuthError) -> (String, ErrorCode) {
+    match error {
+        SteamAuthError::InvalidTicket => (
+            "Steam did not accept your auth ticket. Please restart Steam and the game.".to_string(),
+            ErrorCode::InvalidTicket,
+        ),
+        SteamAuthError::ExpiredTicket => (
+            "Your auth ticket has expired. Please reconnect to get a new one.".to_string(),
+            ErrorCode::ExpiredTicket,
+        ),
+        SteamAuthError::WrongApp => (
+            "Your auth ticket is for another game. Please make sure the game was launched through Steam."
+                .to_string(),
+            ErrorCode::WrongApp,
+        ),
+        SteamAuthError::ApiKey => (
+            "The server can not verify players with Steam right now. Please try again later."
+                .to_string(),
+            ErrorCode::ServerMisconfigured,
+        ),
+    }
+}
+
+async fn send_rejection(
+    messages: &mut impl Transport,
+    error_code: ErrorCode,
+    error_message: String,
+    negotiated_version: u32,
+    min_supported_version: u32,
+) -> Result<(), anyhow::Error> {
+    send_response(
+        messages,
+        HandshakeResponse {
+            success: false,
+            error_message: Some(error_message),
+            negotiated_version,
+            min_supported_version,
+            error_code: Some(error_code),
+        },
+    )
+    .await
+}
+
 #[inline]
 async fn send_response(
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     response: HandshakeResponse,
 ) -> Result<(), anyhow::Error> {
     messages.send(Message::HandshakeResponse(response)).await
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use structopt::StructOpt;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use crate::{codec::MessagesCodec, math::Vector2, steam::MockUser};
+
+    /// Runs a handshake and returns the response as the client read it, and the version the server uses afterwards
+    async fn negotiate(client_version: u32, max_version: u32) -> (HandshakeResponse, u32) {
+        let state = Arc::new(Mutex::new(State::for_tests(vec![MockUser::new(
+            "0102", 7656, "Alice",
+        )])));
+        let max_version = max_version.to_string();
+        let options = LaunchOptions::from_iter(&[
+            "test",
+            "--port",
+            "0",
+            "--max-protocol-version",
+            &max_version,
+        ]);
+
+        // The client speaks the newest shape it knows of, which is ours at most
+        let client_shape = client_version.clamp(protocol::OLDEST_VERSION, protocol::VERSION);
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let mut server_messages = MessagesCodec::new().framed(server_io);
+        let mut client_codec = MessagesCodec::new();
+        client_codec.set_protocol_version(client_shape).unwrap();
+        let mut client_messages = client_codec.framed(client_io);
+
+        let server = async {
+            let request = match server_messages.next().await {
+                Some(Ok(Message::HandshakeRequest(request))) => request,
+                other => panic!("Expected a handshake request, got {:?}", other),
+            };
+
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let address = "127.0.0.1:1234".parse().unwrap();
+            // Rejections are errors, the response tells the test what happened
+            let _ = handle_message(
+                &request,
+                tx,
+                &mut server_messages,
+                &address,
+                &state,
+                &options,
+            )
+            .await;
+            server_messages.protocol_version()
+        };
+
+        let client = async {
+            client_messages
+                .send(Message::HandshakeRequest(HandshakeRequest {
+                    auth_session_ticket: vec![1, 2],
+                    matchmaking_password: None,
+                    level_name: "test".to_string(),
+                    position: Vector2 { x: 0.0, y: 0.0 },
+                    version: client_version,
+                    max_peers: None,
+                }))
+                .await
+                .unwrap();
+
+            match client_messages.next().await {
+                Some(Ok(Message::HandshakeResponse(response))) => response,
+                other => panic!("Expected a handshake response, got {:?}", other),
+            }
+        };
+
+        let (server_version, response) = tokio::join!(server, client);
+        (response, server_version)
+    }
+
+    #[tokio::test]
+    async fn negotiation_matrix() {
+        for max_version in protocol::OLDEST_VERSION..=protocol::VERSION {
+            for client_version in protocol::OLDEST_VERSION - 1..=protocol::VERSION + 1 {
+                let (response, server_version) = negotiate(client_version, max_version).await;
+                let expected_version = client_version.min(max_version);
+                let case = format!("client v{}, max v{}", client_version, max_version);
+
+                if expected_version < protocol::OLDEST_VERSION {
+                    assert!(!response.success, "{}", case);
+                    continue;
+                }
+
+                assert!(response.success, "{}: {:?}", case, response.error_message);
+                assert_eq!(server_version, expected_version, "{}", case);
+
+                // Only newer shapes carry the negotiated version
+                if client_version > protocol::OLDEST_VERSION {
+                    assert_eq!(response.negotiated_version, expected_version, "{}", case);
+                    assert_eq!(
+                        response.min_supported_version,
+                        protocol::OLDEST_VERSION,
+                        "{}",
+                        case
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,21 +1,21 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Context;
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_util::codec::Framed;
+use tokio::sync::Mutex;
 
 use crate::{
     chat::ChatChannel,
     client::Client,
-    codec::MessagesCodec,
-    messages::{IncomingChatMessage, Message, OutgoingChatMessage},
+    handlers::RecoverableError,
+    messages::{ErrorCode, IncomingChatMessage, Message, OutgoingChatMessage},
     state::State,
+    transport::Transport,
     util::string::truncate,
 };
 
 pub async fn handle_message(
     message: &IncomingChatMessage,
-    _messages: &mut Framed<TcpStream, MessagesCodec>,
+    _messages: &mut impl Transport,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
@@ -47,11 +47,21 @@ pub async fn handle_message(
             }
         }
         ChatChannel::Group => {
-            for other_client in state.get_clients_in_group(state.get_matchmaking_options(source)) {
+            let matchmaking_options = state
+                .get_matchmaking_options(source)
+                .context("Client not found")?;
+
+            for other_client in state.get_clients_in_group(matchmaking_options) {
                 target_clients.push(other_client);
             }
         }
-        _ => anyhow::bail!("Unexpected channel"),
+        _ => {
+            return Err(RecoverableError::new(
+                ErrorCode::InvalidChatChannel,
+                "Chat messages can only be sent to the global or group channel",
+            )
+            .into())
+        }
     }
 
     let outgoing_chat_message = OutgoingChatMessage {
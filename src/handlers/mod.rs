@@ -1,18 +1,23 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{fmt::Display, net::SocketAddr, sync::Arc};
 
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_util::codec::Framed;
+use tokio::sync::Mutex;
 
-use crate::{codec::MessagesCodec, messages::Message, state::State};
+use crate::{
+    messages::{ErrorCode, Message},
+    state::State,
+    transport::Transport,
+};
 
 pub mod handshake;
 pub mod incoming_chat_message;
+pub mod ping;
 pub mod position_update;
+pub mod set_matchmaking_options;
 pub mod set_matchmaking_password;
 
 pub async fn handle_message(
     message: &Message,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
@@ -24,9 +29,43 @@ pub async fn handle_message(
         Message::SetMatchmakingPassword(val) => {
             set_matchmaking_password::handle_message(val, messages, source, state).await
         }
+        Message::SetMatchmakingOptions(val) => {
+            set_matchmaking_options::handle_message(val, messages, source, state).await
+        }
         Message::IncomingChatMessage(val) => {
             incoming_chat_message::handle_message(val, messages, source, state).await
         }
-        _ => anyhow::bail!("Unexpected message"),
+        Message::Ping(val) => ping::handle_message(val, messages, source, state).await,
+        Message::Pong(_) => Ok(()), // Receiving anything keeps the connection alive, so there's nothing left to do
+        _ => Err(RecoverableError::new(
+            ErrorCode::UnexpectedMessage,
+            "The server does not expect this message right now",
+        )
+        .into()),
     }
 }
+
+/// A handler error the client is told about while staying connected.
+/// Any other error returned from a handler disconnects the client.
+#[derive(Debug)]
+pub struct RecoverableError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RecoverableError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for RecoverableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RecoverableError {}
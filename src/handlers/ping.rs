@@ -0,0 +1,23 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures::SinkExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    messages::{Message, Ping, Pong},
+    state::State,
+    transport::Transport,
+};
+
+pub async fn handle_message(
+    message: &Ping,
+    messages: &mut impl Transport,
+    _source: &SocketAddr,
+    _state: &Arc<Mutex<State>>,
+) -> Result<(), anyhow::Error> {
+    messages
+        .send(Message::Pong(Pong {
+            sequence: message.sequence,
+        }))
+        .await
+}
@@ -1,32 +1,32 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Context;
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_util::codec::Framed;
+use tokio::sync::Mutex;
 
-use crate::{codec::MessagesCodec, messages::PositionUpdate, state::State};
+use crate::{messages::PositionUpdate, protocol, state::State, transport::Transport};
 
 pub async fn handle_message(
     message: &PositionUpdate,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
     tracing::info!("{:?}", message);
 
-    let steam_id: u64;
     let mut state = state.lock().await;
-    {
-        let mut client = state.get_client_mut(source).context("Client not found")?;
-        client.position = message.position;
-        steam_id = client.steam_id;
-    }
+    let steam_id = state
+        .update_position(source, message.position)
+        .context("Client not found")?
+        .steam_id;
 
-    let matchmaking_options = state.get_matchmaking_options(source);
-    let nearby_clients = state.get_nearby_clients(&message.position, matchmaking_options);
+    // Newer clients are told about changes as they happen, older ones get everyone nearby on every update
+    if messages.protocol_version() < protocol::NEARBY_DELTAS_VERSION {
+        let nearby_clients = state.get_nearby_clients(source);
 
-    if nearby_clients.len() > 1 {
-        crate::util::networking::send_nearby_clients(&steam_id, messages, &nearby_clients).await?;
+        if !nearby_clients.is_empty() {
+            crate::util::networking::send_nearby_clients(&steam_id, messages, &nearby_clients)
+                .await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,41 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use futures::SinkExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    messages::{Message, ServerStatusUpdate, SetMatchmakingOptions},
+    state::{MatchmakingOptions, State},
+    transport::Transport,
+};
+
+pub async fn handle_message(
+    message: &SetMatchmakingOptions,
+    messages: &mut impl Transport,
+    source: &SocketAddr,
+    state: &Arc<Mutex<State>>,
+) -> Result<(), anyhow::Error> {
+    let mut state = state.lock().await;
+
+    // Players restricted to solo play stay isolated in the new level too
+    let matchmaking_options = MatchmakingOptions {
+        password: message.password.clone(),
+        level_name: message.level_name.clone(),
+        ..state
+            .get_matchmaking_options(source)
+            .context("Client not found")?
+            .clone()
+    };
+    state.set_matchmaking_options(source, Some(matchmaking_options));
+
+    messages
+        .send(Message::ServerStatusUpdate(ServerStatusUpdate {
+            total_players: state.get_clients_iter().len() as u32,
+            group_players: state
+                .get_matchmaking_options(source)
+                .map_or(0, |options| state.get_clients_in_group(options).count())
+                as u32,
+        }))
+        .await
+}
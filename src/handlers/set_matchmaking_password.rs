@@ -1,31 +1,43 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_util::codec::Framed;
+use anyhow::Context;
+use tokio::sync::Mutex;
 
 use crate::{
-    codec::MessagesCodec,
     messages::SetMatchmakingPassword,
+    protocol,
     state::{MatchmakingOptions, State},
+    transport::Transport,
 };
 
 pub async fn handle_message(
     message: &SetMatchmakingPassword,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
     let mut state = state.lock().await;
-    let level_name = state.get_matchmaking_options(source).level_name.clone();
-    let matchmaking_options = MatchmakingOptions::new(message.password.clone(), level_name);
-    state.set_matchmaking_options(source, Some(matchmaking_options.clone()));
+    let matchmaking_options = MatchmakingOptions {
+        password: message.password.clone(),
+        ..state
+            .get_matchmaking_options(source)
+            .context("Client not found")?
+            .clone()
+    };
+    state.set_matchmaking_options(source, Some(matchmaking_options));
 
-    let client = state.get_client(source).unwrap();
-    let nearby_clients = state.get_nearby_clients(&client.position, &matchmaking_options);
+    if messages.protocol_version() < protocol::NEARBY_DELTAS_VERSION {
+        let client = state.get_client(source).context("Client not found")?;
+        let nearby_clients = state.get_nearby_clients(source);
 
-    if nearby_clients.len() > 1 {
-        crate::util::networking::send_nearby_clients(&client.steam_id, messages, &nearby_clients)
+        if !nearby_clients.is_empty() {
+            crate::util::networking::send_nearby_clients(
+                &client.steam_id,
+                messages,
+                &nearby_clients,
+            )
             .await?;
+        }
     }
 
     Ok(())
@@ -1,11 +1,14 @@
-use futures::{SinkExt, StreamExt};
+use futures::{future, SinkExt, StreamExt};
 use handlers::handshake;
-use std::{net::SocketAddr, sync::Arc};
+use socket2::{SockRef, TcpKeepalive};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use structopt::StructOpt;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
     signal,
-    sync::{mpsc, Mutex},
+    sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
 };
 use tokio_cron_scheduler::{Job, JobScheduler};
 use tokio_util::codec::Decoder;
@@ -16,48 +19,89 @@ use codec::MessagesCodec;
 mod messages;
 use messages::Message;
 
+mod options;
+use options::LaunchOptions;
+
 mod state;
 use state::State;
 
-use crate::messages::ServerStatusUpdate;
+use crate::messages::{
+    ErrorCode, Ping, PlayerNameChanged, ServerError, ServerShutdown, ServerStatusUpdate,
+};
 
 mod client;
 
 mod handlers;
+use handlers::RecoverableError;
+
+mod bans;
+use bans::BanList;
 
 mod chat;
 mod encoding;
 mod math;
+mod metrics;
+mod profiles;
+use profiles::LevelProfiles;
+
+mod protocol;
 mod steam;
+use steam::{MockSteamApi, SteamApi, WebSteamApi};
+
+mod tickets;
+use tickets::UsedTickets;
+
+mod tls;
+mod transport;
+use transport::{websocket::WebSocketTransport, Transport};
+
 mod util;
 
 type MessageType = Message;
 
-#[derive(StructOpt)]
-#[structopt(
-    name = "JKMP Matchmaking Server",
-    about = "Handles matchmaking between players"
-)]
-struct LaunchOptions {
-    #[structopt(short, long, default_value = "0.0.0.0")]
-    host: String,
-
-    #[structopt(short, long)]
-    port: u16,
-}
-
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     tracing_subscriber::fmt::init();
 
-    let options = LaunchOptions::from_args();
+    let options = Arc::new(LaunchOptions::from_args());
+    options.validate()?;
 
-    if std::env::var("STEAM_API_KEY").is_err() {
-        anyhow::bail!("Environment variable STEAM_API_KEY is missing");
-    }
+    let steam = create_steam_api(&options)?;
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            tracing::info!("Tls enabled, using certificate {}", cert_path.display());
+            Some(tls::create_acceptor(cert_path, key_path)?)
+        }
+        _ => None,
+    };
+
+    let ban_list = match &options.ban_list {
+        Some(path) => {
+            let ban_list = BanList::load(path)?;
+            tracing::info!("Loaded {} banned steam ids", ban_list.len());
+            ban_list
+        }
+        None => BanList::default(),
+    };
 
     let listener = TcpListener::bind(format!("{}:{}", options.host, options.port)).await?;
-    let state = Arc::new(Mutex::new(State::new()));
+    let level_profiles = match &options.level_profiles {
+        Some(path) => {
+            let level_profiles = LevelProfiles::load(path)?;
+            tracing::info!("Loaded {} level profiles", level_profiles.len());
+            level_profiles
+        }
+        None => LevelProfiles::default(),
+    };
+
+    let used_tickets = UsedTickets::new(options.ticket_reuse_window());
+    let state = Arc::new(Mutex::new(State::new(
+        steam,
+        ban_list,
+        used_tickets,
+        level_profiles,
+    )));
 
     tracing::info!(
         "Server started, listening for clients on {}:{}",
@@ -65,6 +109,20 @@ async fn main() -> Result<(), anyhow::Error> {
         options.port
     );
 
+    let websocket_listener = match options.websocket_port {
+        Some(port) => {
+            let websocket_listener =
+                TcpListener::bind(format!("{}:{}", options.host, port)).await?;
+            tracing::info!(
+                "Listening for websocket clients on {}:{}",
+                options.host,
+                port
+            );
+            Some(websocket_listener)
+        }
+        None => None,
+    };
+
     let mut scheduler = JobScheduler::new();
     let scheduler_state = state.clone();
 
@@ -83,32 +141,274 @@ async fn main() -> Result<(), anyhow::Error> {
     .unwrap();
     scheduler.add(broadcast_server_status_job).unwrap();
 
+    let scheduler_state = state.clone();
+
+    // Pick up steam name changes once every five minutes
+    let refresh_player_names_job = Job::new_async("0 0/5 * * * *", move |_uuid, _l| {
+        let state = scheduler_state.clone();
+        Box::pin(async move {
+            // Steam requests can't be shared between threads, which jobs require, so they run on their own task
+            tokio::spawn(async move {
+                if let Err(error) = refresh_player_names(state).await {
+                    tracing::error!("An error occured while refreshing player names: {}", error);
+                }
+            });
+        })
+    })
+    .unwrap();
+    scheduler.add(refresh_player_names_job).unwrap();
+
     scheduler.start();
 
+    let pending_handshakes = Arc::new(Semaphore::new(options.max_pending_handshakes));
+
     loop {
-        tokio::select! {
-            result = listener.accept() => match result {
-                Err(error) => tracing::info!("An error occurred when accepting socket: {}", error),
-                Ok((socket, address)) => {
-                    let state = state.clone();
-                    tokio::spawn(async move {
-                        process_client(socket, address, state).await;
-                    });
-                }
-            },
+        let (result, kind) = tokio::select! {
+            result = listener.accept() => (result, ListenerKind::Tcp),
+            result = accept_optional(&websocket_listener) => (result, ListenerKind::WebSocket),
             _ = signal::ctrl_c() => {
                 break;
             }
+        };
+
+        let (socket, address) = match result {
+            Ok(connection) => connection,
+            Err(error) => {
+                tracing::info!("An error occurred when accepting socket: {}", error);
+                continue;
+            }
+        };
+
+        if let Err(error) = enable_keepalive(&socket, &options) {
+            tracing::warn!("Could not enable tcp keepalive for {}: {}", address, error);
         }
+
+        let handshake_permit = match pending_handshakes.clone().try_acquire_owned() {
+            Ok(handshake_permit) => handshake_permit,
+            Err(_) => {
+                let counter = &metrics::PENDING_HANDSHAKES_REJECTED;
+                tracing::warn!(
+                    metric = counter.name(),
+                    total = counter.increment(),
+                    "Dropping connection from {}, too many pending handshakes",
+                    address
+                );
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        let options = options.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            // Tls, websocket and matchmaking handshakes share one deadline so stacking them can't be used to stall longer
+            let deadline = Instant::now() + options.handshake_timeout();
+
+            match tls_acceptor {
+                Some(tls_acceptor) => {
+                    match time::timeout_at(deadline, tls_acceptor.accept(socket)).await {
+                        Ok(Ok(socket)) => {
+                            accept_client(
+                                socket,
+                                kind,
+                                address,
+                                deadline,
+                                state,
+                                options,
+                                handshake_permit,
+                            )
+                            .await;
+                        }
+                        Ok(Err(error)) => {
+                            tracing::warn!("Tls handshake with {} failed: {}", address, error);
+                        }
+                        Err(_) => log_handshake_timeout("Tls", &address),
+                    }
+                }
+                None => {
+                    accept_client(
+                        socket,
+                        kind,
+                        address,
+                        deadline,
+                        state,
+                        options,
+                        handshake_permit,
+                    )
+                    .await;
+                }
+            }
+        });
     }
 
+    // Stop accepting new connections while the existing ones drain
+    drop(listener);
+    drop(websocket_listener);
+
     tracing::info!("Server shutting down...");
 
-    // todo: send message about shutdown to clients?
+    shutdown(state, &options).await;
 
     Ok(())
 }
 
+fn create_steam_api(options: &LaunchOptions) -> Result<Arc<dyn SteamApi>, anyhow::Error> {
+    #[cfg(feature = "dev-auth")]
+    if let Some(secret) = &options.dev_auth_secret {
+        if cfg!(not(debug_assertions)) && !options.allow_dev_auth_in_release {
+            anyhow::bail!("Dev auth is disabled in release builds, pass --allow-dev-auth-in-release to use it anyway");
+        }
+
+        tracing::warn!("Using dev auth, players can log in as anyone who knows the secret");
+        return Ok(Arc::new(steam::DevAuthSteamApi::new(secret.clone())));
+    }
+
+    match &options.mock_steam {
+        Some(path) => {
+            tracing::warn!("Using mock steam users from {}", path.display());
+            Ok(Arc::new(MockSteamApi::load(path)?))
+        }
+        None => Ok(Arc::new(WebSteamApi::from_env(
+            &options.steam_api_url,
+            options.steam_app_id,
+            options.steam_summary_cache_ttl(),
+        )?)),
+    }
+}
+
+enum ListenerKind {
+    Tcp,
+    WebSocket,
+}
+
+/// Sets up the transport for the connection depending on which listener it came from, then processes the client
+async fn accept_client<S>(
+    socket: S,
+    kind: ListenerKind,
+    address: SocketAddr,
+    deadline: Instant,
+    state: Arc<Mutex<State>>,
+    options: Arc<LaunchOptions>,
+    handshake_permit: OwnedSemaphorePermit,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match kind {
+        ListenerKind::Tcp => {
+            let messages = MessagesCodec::new().framed(socket);
+            process_client(
+                messages,
+                address,
+                deadline,
+                state,
+                options,
+                handshake_permit,
+            )
+            .await;
+        }
+        ListenerKind::WebSocket => {
+            match time::timeout_at(deadline, WebSocketTransport::accept(socket)).await {
+                Ok(Ok(messages)) => {
+                    process_client(
+                        messages,
+                        address,
+                        deadline,
+                        state,
+                        options,
+                        handshake_permit,
+                    )
+                    .await;
+                }
+                Ok(Err(error)) => {
+                    tracing::warn!("Websocket handshake with {} failed: {}", address, error);
+                }
+                Err(_) => log_handshake_timeout("Websocket", &address),
+            }
+        }
+    }
+}
+
+fn log_handshake_timeout(handshake: &str, address: &SocketAddr) {
+    let counter = &metrics::HANDSHAKE_TIMEOUTS;
+    tracing::warn!(
+        metric = counter.name(),
+        total = counter.increment(),
+        "{} handshake with {} did not complete in time",
+        handshake,
+        address
+    );
+}
+
+/// Has the os probe silent connections, so ones whose other end vanished without closing them are dropped
+fn enable_keepalive(socket: &TcpStream, options: &LaunchOptions) -> Result<(), std::io::Error> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(options.tcp_keepalive())
+        .with_interval(options.tcp_keepalive());
+
+    // Linux defaults to 9 probes, which keeps dead peers around for minutes. Windows always sends 10
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))]
+    let keepalive = keepalive.with_retries(3);
+
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+/// Accepts a connection from the listener if there is one, otherwise never completes
+async fn accept_optional(
+    listener: &Option<TcpListener>,
+) -> Result<(TcpStream, SocketAddr), std::io::Error> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Tells every client the server is shutting down and waits for them to disconnect, or until the drain period is over
+async fn shutdown(state: Arc<Mutex<State>>, options: &LaunchOptions) {
+    let shutdown_message = ServerShutdown {
+        reason: "Server is restarting".into(),
+        reconnect_after_secs: options.shutdown_reconnect_after,
+    };
+
+    {
+        let state = state.lock().await;
+        tracing::info!(
+            "Notifying {} clients about shutdown",
+            state.get_clients_iter().len()
+        );
+
+        for (_, client) in state.get_clients_iter() {
+            // Ignore failed sends
+            let _ = client.send(Message::ServerShutdown(shutdown_message.clone()));
+        }
+    }
+
+    let drain = async {
+        let mut interval = time::interval(Duration::from_millis(100));
+
+        loop {
+            interval.tick().await;
+
+            if state.lock().await.get_clients_iter().len() == 0 {
+                break;
+            }
+        }
+    };
+
+    match time::timeout(options.shutdown_drain(), drain).await {
+        Ok(_) => tracing::info!("All clients disconnected"),
+        Err(_) => tracing::info!(
+            "{} clients still connected after drain period, closing anyway",
+            state.lock().await.get_clients_iter().len()
+        ),
+    }
+}
+
 async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow::Error> {
     let state = state.lock().await;
 
@@ -121,8 +421,9 @@ async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow:
 
     for client in clients {
         let group_players = state
-            .get_clients_in_group(state.get_matchmaking_options(client.0))
-            .count() as u32;
+            .get_matchmaking_options(client.0)
+            .map_or(0, |options| state.get_clients_in_group(options).count())
+            as u32;
 
         // Ignore failed sends
         let _ = client
@@ -136,18 +437,103 @@ async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow:
     Ok(())
 }
 
-#[tracing::instrument(skip(socket, state))]
-async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex<State>>) {
+async fn refresh_player_names(state: Arc<Mutex<State>>) -> Result<(), anyhow::Error> {
+    // Don't hold the lock while waiting for steam
+    let (steam, steam_ids) = {
+        let state = state.lock().await;
+        let steam_ids: Vec<u64> = state
+            .get_clients_iter()
+            .map(|(_, client)| client.steam_id)
+            .collect();
+        (state.steam(), steam_ids)
+    };
+
+    if steam_ids.is_empty() {
+        return Ok(());
+    }
+
+    // Skip the cache, it would hide renames until its entries expire
+    let summaries = steam.refresh_player_summaries(steam_ids).await?;
+
+    let mut state = state.lock().await;
+    let mut renamed = Vec::new();
+
+    // Clients that connected since the names were fetched are simply missing from the summaries
+    for (address, client) in state.get_clients_iter_mut() {
+        let summary = match summaries.get(&client.steam_id) {
+            Some(summary) if summary.name != client.name => summary,
+            _ => continue,
+        };
+
+        tracing::info!("{} changed their name to {}", client, summary.name);
+        client.name = summary.name.clone();
+
+        renamed.push((
+            *address,
+            PlayerNameChanged {
+                steam_id: summary.steam_id,
+                name: summary.name.clone(),
+            },
+        ));
+    }
+
+    for (address, name_changed) in renamed {
+        let matchmaking_options = match state.get_matchmaking_options(&address) {
+            Some(matchmaking_options) => matchmaking_options,
+            None => continue,
+        };
+
+        for client in state.get_clients_in_group(matchmaking_options) {
+            // Ignore failed sends
+            let _ = client.send(Message::PlayerNameChanged(name_changed.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(messages, deadline, state, options, handshake_permit))]
+async fn process_client(
+    mut messages: impl Transport,
+    address: SocketAddr,
+    deadline: Instant,
+    state: Arc<Mutex<State>>,
+    options: Arc<LaunchOptions>,
+    handshake_permit: OwnedSemaphorePermit,
+) {
     let (tx, mut rx) = mpsc::unbounded_channel::<MessageType>();
-    let mut messages = MessagesCodec::new().framed(socket);
 
-    match messages.next().await {
+    let handshake = match time::timeout_at(deadline, messages.next()).await {
+        Ok(handshake) => handshake,
+        Err(_) => {
+            let counter = &metrics::HANDSHAKE_TIMEOUTS;
+            tracing::warn!(
+                metric = counter.name(),
+                total = counter.increment(),
+                "Did not receive a handshake within {:?}",
+                options.handshake_timeout()
+            );
+            return;
+        }
+    };
+
+    match handshake {
         Some(Ok(message)) => match message {
             Message::HandshakeRequest(request) => {
-                if let Err(error) =
-                    handshake::handle_message(&request, tx, &mut messages, &address, &state).await
+                if let Err(error) = handshake::handle_message(
+                    &request,
+                    tx,
+                    &mut messages,
+                    &address,
+                    &state,
+                    &options,
+                )
+                .await
                 {
                     tracing::warn!("An error occurred while handling handshake: {:?}", error);
+
+                    // The handshake can fail after the client was added, and nothing else would remove it
+                    state.lock().await.remove_client(&address);
                     return;
                 }
             }
@@ -165,23 +551,99 @@ async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex
         }
     }
 
+    drop(handshake_permit);
+
+    // Older clients don't answer pings, so they can't be told apart from idle ones. Tcp keepalive drops them if they're gone
+    let heartbeat_enabled = messages.protocol_version() >= protocol::HEARTBEAT_VERSION;
+    let idle_timeout = options.idle_timeout();
+    let idle_deadline = time::sleep(idle_timeout);
+    tokio::pin!(idle_deadline);
+    let mut ping_interval = time::interval(options.ping_interval());
+    let mut ping_sequence: u32 = 0;
+
     loop {
         tokio::select! {
             Some(outbound_message) = rx.recv() => {
+                // The connection is closed right after telling the client the server is going away or that it was kicked
+                let is_final = matches!(
+                    outbound_message,
+                    Message::ServerShutdown(_) | Message::Kicked(_)
+                );
+
                 if let Err(error) = messages.send(outbound_message).await {
                     tracing::warn!("Failed to send message: {:?}", error);
                     break; // Client disconnected
                 }
+
+                if is_final {
+                    break;
+                }
+            },
+            _ = ping_interval.tick(), if heartbeat_enabled => {
+                ping_sequence = ping_sequence.wrapping_add(1);
+                if let Err(error) = messages.send(Message::Ping(Ping { sequence: ping_sequence })).await {
+                    tracing::warn!("Failed to send ping: {:?}", error);
+                    break; // Client disconnected
+                }
+            },
+            _ = &mut idle_deadline, if heartbeat_enabled => {
+                tracing::info!("Client has not sent anything in {:?}, disconnecting", idle_timeout);
+                break;
             },
             result = messages.next() => match result {
                 Some(Ok(message)) => {
+                    idle_deadline.as_mut().reset(Instant::now() + idle_timeout);
+
                     if let Err(error) = handlers::handle_message(&message, &mut messages, &address, &state).await {
-                        tracing::warn!("An error occured when handling message: {:?}", error);
-                        break;
+                        // A session kicked for a duplicate login is removed from State before it gets to deliver Kicked
+                        if state.lock().await.get_client(&address).is_none() {
+                            tracing::info!("Session ended while handling a message: {:#}", error);
+
+                            while let Ok(outbound_message) = rx.try_recv() {
+                                if messages.send(outbound_message).await.is_err() {
+                                    break; // Client disconnected
+                                }
+                            }
+                            break;
+                        }
+
+                        match error.downcast::<RecoverableError>() {
+                            Ok(error) => {
+                                tracing::info!("Client sent a message that could not be handled: {}", error);
+
+                                let server_error = ServerError {
+                                    code: error.code,
+                                    message: error.message,
+                                    fatal: false,
+                                };
+                                if let Err(error) = messages.send(Message::ServerError(server_error)).await {
+                                    tracing::warn!("Failed to send message: {:?}", error);
+                                    break; // Client disconnected
+                                }
+                            }
+                            Err(error) => {
+                                tracing::warn!("An error occured when handling message: {:?}", error);
+
+                                // Ignore failed sends, the connection is closed either way
+                                let _ = messages.send(Message::ServerError(ServerError {
+                                    code: ErrorCode::Unknown,
+                                    message: "An unexpected error occured on the server".to_string(),
+                                    fatal: true,
+                                })).await;
+                                break;
+                            }
+                        }
                     }
                 },
                 Some(Err(error)) => {
                     tracing::warn!("An error occured when reading message: {:?}", error);
+
+                    // Ignore failed sends, the connection is closed either way
+                    let _ = messages.send(Message::ServerError(ServerError {
+                        code: ErrorCode::InvalidMessage,
+                        message: "The server could not read a message".to_string(),
+                        fatal: true,
+                    })).await;
                     break;
                 },
                 None => break // Client disconnected
@@ -195,3 +657,193 @@ async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex
         tracing::info!("{} disconnected", client);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        math::Vector2,
+        messages::{HandshakeRequest, PositionUpdate},
+        steam::MockUser,
+    };
+
+    use super::*;
+
+    fn create_state() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State::for_tests(vec![MockUser::new(
+            "0102", 7656, "Alice",
+        )])))
+    }
+
+    fn create_options(args: &[&str]) -> Arc<LaunchOptions> {
+        let args = ["test", "--port", "0"].iter().chain(args);
+        Arc::new(LaunchOptions::from_iter(args))
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn handshake_request() -> Message {
+        Message::HandshakeRequest(HandshakeRequest {
+            auth_session_ticket: vec![1, 2],
+            matchmaking_password: None,
+            level_name: "test".to_string(),
+            position: Vector2 { x: 0.0, y: 0.0 },
+            version: protocol::VERSION,
+            max_peers: None,
+        })
+    }
+
+    /// Runs a session on its own task and sends its handshake, returning the client end
+    async fn start_session(
+        state: &Arc<Mutex<State>>,
+        options: &Arc<LaunchOptions>,
+        port: u16,
+    ) -> Framed<DuplexStream, MessagesCodec> {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let handshake_permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+
+        tokio::spawn(process_client(
+            MessagesCodec::new().framed(server_io),
+            address(port),
+            Instant::now() + Duration::from_secs(5),
+            state.clone(),
+            options.clone(),
+            handshake_permit,
+        ));
+
+        let mut client_messages = MessagesCodec::new().framed(client_io);
+        client_messages.send(handshake_request()).await.unwrap();
+        client_messages
+    }
+
+    /// Reads messages until one matches, panicking on server errors or if the connection closes first
+    async fn read_until(
+        messages: &mut Framed<DuplexStream, MessagesCodec>,
+        matches: impl Fn(&Message) -> bool,
+    ) -> Message {
+        loop {
+            match messages.next().await {
+                Some(Ok(message)) if matches(&message) => return message,
+                Some(Ok(Message::ServerError(error))) => panic!("Got a server error {:?}", error),
+                Some(Ok(_)) => continue,
+                other => panic!("Connection ended with {:?}", other),
+            }
+        }
+    }
+
+    async fn read_handshake_response(
+        messages: &mut Framed<DuplexStream, MessagesCodec>,
+    ) -> messages::HandshakeResponse {
+        match read_until(messages, |message| {
+            matches!(message, Message::HandshakeResponse(_))
+        })
+        .await
+        {
+            Message::HandshakeResponse(response) => response,
+            _ => unreachable!(),
+        }
+    }
+
+    async fn connected_addresses(state: &Arc<Mutex<State>>) -> Vec<SocketAddr> {
+        let state = state.lock().await;
+        state
+            .get_clients_iter()
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_does_not_leave_the_client_behind() {
+        let state = create_state();
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let mut client_messages = MessagesCodec::new().framed(client_io);
+
+        // Leave before the server can answer, so the handshake fails after the client was added
+        client_messages.send(handshake_request()).await.unwrap();
+        drop(client_messages);
+
+        process_client(
+            MessagesCodec::new().framed(server_io),
+            address(1),
+            Instant::now() + Duration::from_secs(5),
+            state.clone(),
+            create_options(&[]),
+            Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap(),
+        )
+        .await;
+
+        assert!(connected_addresses(&state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn kick_old_policy_kicks_the_first_session() {
+        let state = create_state();
+        let options = create_options(&["--duplicate-login-policy", "kick-old"]);
+
+        let mut first = start_session(&state, &options, 1).await;
+        assert!(read_handshake_response(&mut first).await.success);
+
+        let mut second = start_session(&state, &options, 2).await;
+        assert!(read_handshake_response(&mut second).await.success);
+
+        read_until(&mut first, |message| matches!(message, Message::Kicked(_))).await;
+        assert_eq!(connected_addresses(&state).await, [address(2)]);
+    }
+
+    #[tokio::test]
+    async fn reject_new_policy_rejects_the_second_session() {
+        let state = create_state();
+        let options = create_options(&["--duplicate-login-policy", "reject-new"]);
+
+        let mut first = start_session(&state, &options, 1).await;
+        assert!(read_handshake_response(&mut first).await.success);
+
+        let mut second = start_session(&state, &options, 2).await;
+        let response = read_handshake_response(&mut second).await;
+
+        assert!(!response.success);
+        assert_eq!(response.error_code, Some(ErrorCode::AlreadyConnected));
+        assert_eq!(connected_addresses(&state).await, [address(1)]);
+    }
+
+    #[tokio::test]
+    async fn kicked_session_ends_with_kicked_while_handling_a_message() {
+        // Which of the two pending events the session picks first is random, so give both orders a chance
+        for _ in 0..20 {
+            let state = create_state();
+            let options = create_options(&[]);
+
+            let mut old = start_session(&state, &options, 1).await;
+            read_until(&mut old, |message| {
+                matches!(message, Message::ServerStatusUpdate(_))
+            })
+            .await;
+
+            // Kick the session the way a duplicate login does, with a position update already on its way
+            {
+                let mut state = state.lock().await;
+                let client = state.remove_client(&address(1)).unwrap();
+
+                old.send(Message::PositionUpdate(PositionUpdate {
+                    position: Vector2 { x: 1.0, y: 0.0 },
+                }))
+                .await
+                .unwrap();
+
+                client
+                    .send(Message::Kicked(messages::Kicked {
+                        reason: "You connected from another location.".to_string(),
+                    }))
+                    .unwrap();
+            }
+
+            read_until(&mut old, |message| matches!(message, Message::Kicked(_))).await;
+        }
+    }
+}
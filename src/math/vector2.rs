@@ -18,3 +18,11 @@ impl Display for Vector2 {
         write!(f, "({}, {})", self.x, self.y)
     }
 }
+
+impl Vector2 {
+    /// The squared distance to the other point. Cheaper than the distance and compares the same way
+    pub fn distance_squared(&self, other: &Vector2) -> f32 {
+        let (dx, dy) = (self.x - other.x, self.y - other.y);
+        dx * dx + dy * dy
+    }
+}
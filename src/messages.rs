@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{chat::ChatChannel, math::Vector2};
+use crate::{chat::ChatChannel, math::Vector2, util::steam_id};
 
 // Allows incoming and outgoing chat message variants to end in "Message"
 // without warning us about enum variants being suffixed by the same name as the enum
@@ -15,6 +15,15 @@ pub enum Message {
     IncomingChatMessage(IncomingChatMessage),
     OutgoingChatMessage(OutgoingChatMessage),
     ServerStatusUpdate(ServerStatusUpdate),
+    Ping(Ping),
+    Pong(Pong),
+    ServerShutdown(ServerShutdown),
+    PlayerNameChanged(PlayerNameChanged),
+    Kicked(Kicked),
+    ServerError(ServerError),
+    NearbyClientsAdded(NearbyClientsAdded),
+    NearbyClientsRemoved(NearbyClientsRemoved),
+    SetMatchmakingOptions(SetMatchmakingOptions),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,12 +33,49 @@ pub struct HandshakeRequest {
     pub level_name: String,
     pub position: Vector2,
     pub version: u32,
+    /// The most players the client wants to be matched with at once. The server may lower it
+    pub max_peers: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HandshakeResponse {
     pub success: bool,
     pub error_message: Option<String>,
+    /// The protocol version the server will use for the rest of the connection
+    pub negotiated_version: u32,
+    /// The oldest protocol version the server currently accepts
+    pub min_supported_version: u32,
+    pub error_code: Option<ErrorCode>,
+}
+
+/// Tells the client why something failed, so it can react without parsing the error message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Unknown,
+    /// Steam could not be reached. The client should try again in a little while
+    SteamUnavailable,
+    /// Steam did not accept the auth ticket. Restarting steam gets the client a new one
+    InvalidTicket,
+    /// The auth ticket has expired. The client should get a new one and connect again
+    ExpiredTicket,
+    /// The auth ticket was issued for a different app than the server expects
+    WrongApp,
+    /// The server can't verify anyone with steam right now. Nothing the client can do but wait
+    ServerMisconfigured,
+    /// The client speaks an older protocol version than the server accepts
+    OutdatedVersion,
+    /// The player or the owner of the game is banned
+    Banned,
+    /// The auth ticket was already used by someone else
+    TicketReused,
+    /// The steam account is already connected and the server doesn't kick the old session
+    AlreadyConnected,
+    /// The message was understood, but isn't allowed at this point
+    UnexpectedMessage,
+    /// The message could not be read
+    InvalidMessage,
+    /// Chat messages can't be sent to that channel
+    InvalidChatChannel,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,6 +90,7 @@ pub struct SetMatchmakingPassword {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InformNearbyClients {
+    #[serde(with = "steam_id::vec")]
     pub client_ids: Vec<u64>,
 }
 
@@ -56,6 +103,7 @@ pub struct IncomingChatMessage {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OutgoingChatMessage {
     pub channel: ChatChannel,
+    #[serde(with = "steam_id::option")]
     pub sender_id: Option<u64>,
     pub sender_name: Option<String>,
     pub message: String,
@@ -66,3 +114,63 @@ pub struct ServerStatusUpdate {
     pub total_players: u32,
     pub group_players: u32,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub sequence: u32,
+}
+
+/// Reply to a `Ping`, echoing its sequence number
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub sequence: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerShutdown {
+    pub reason: String,
+    /// How long the client should wait before trying to reconnect
+    pub reconnect_after_secs: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerNameChanged {
+    #[serde(with = "steam_id")]
+    pub steam_id: u64,
+    pub name: String,
+}
+
+/// Sent right before the server closes the connection of a client it no longer wants around
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Kicked {
+    pub reason: String,
+}
+
+/// Reports a failure outside of the handshake. The connection is closed after fatal errors
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub fatal: bool,
+}
+
+/// Players that came into range of the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NearbyClientsAdded {
+    #[serde(with = "steam_id::vec")]
+    pub client_ids: Vec<u64>,
+}
+
+/// Players that left range of the client or disconnected
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NearbyClientsRemoved {
+    #[serde(with = "steam_id::vec")]
+    pub client_ids: Vec<u64>,
+}
+
+/// Moves the client to another group, changing the level and password at once
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMatchmakingOptions {
+    pub level_name: String,
+    pub password: Option<String>,
+}
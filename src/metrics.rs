@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A monotonically increasing counter that is reported alongside the tracing event that bumps it.
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    /// Increments the counter and returns the new total
+    pub fn increment(&self) -> u64 {
+        self.value.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Connections dropped because they did not send a handshake in time
+pub static HANDSHAKE_TIMEOUTS: Counter = Counter::new("handshake_timeouts");
+
+/// Connections refused because too many other connections were already waiting to complete their handshake
+pub static PENDING_HANDSHAKES_REJECTED: Counter = Counter::new("pending_handshakes_rejected");
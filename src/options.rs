@@ -0,0 +1,230 @@
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "JKMP Matchmaking Server",
+    about = "Handles matchmaking between players"
+)]
+pub struct LaunchOptions {
+    #[structopt(short, long, default_value = "0.0.0.0")]
+    pub host: String,
+
+    #[structopt(short, long)]
+    pub port: u16,
+
+    /// Also accept websocket connections on this port, for web clients that can't open raw tcp sockets
+    #[structopt(long)]
+    pub websocket_port: Option<u16>,
+
+    /// Path to a PEM encoded certificate chain. Enables tls on all listeners when given together with --tls-key
+    #[structopt(long, parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key of the tls certificate
+    #[structopt(long, parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+
+    /// Path to a json file of fake steam users. Replaces the Steam Web API, so STEAM_API_KEY isn't needed
+    #[structopt(long, parse(from_os_str))]
+    pub mock_steam: Option<PathBuf>,
+
+    /// Shared secret for signing dev auth tickets. Replaces the Steam Web API, so STEAM_API_KEY isn't needed
+    #[cfg(feature = "dev-auth")]
+    #[structopt(long, env = "DEV_AUTH_SECRET")]
+    pub dev_auth_secret: Option<String>,
+
+    /// Dev auth is refused in release builds unless this is set
+    #[cfg(feature = "dev-auth")]
+    #[structopt(long)]
+    pub allow_dev_auth_in_release: bool,
+
+    /// Where the Steam Web API is hosted. Can be pointed at a fake server for testing
+    #[structopt(
+        long,
+        env = "STEAM_API_URL",
+        default_value = "https://api.steampowered.com"
+    )]
+    pub steam_api_url: String,
+
+    /// The app id auth tickets are verified against. Set it to serve a different branch of the game
+    #[structopt(long, env = "STEAM_APP_ID", default_value = "1061090")]
+    pub steam_app_id: u32,
+
+    /// How long player names fetched from steam are reused before asking steam again, in seconds
+    #[structopt(long, default_value = "300")]
+    pub steam_summary_cache_ttl: u64,
+
+    /// What to do with VAC or publisher banned players: allow, reject or solo (connect, but never matchmake with anyone)
+    #[structopt(long, default_value = "allow")]
+    pub ban_policy: BanPolicy,
+
+    /// Path to a file of banned steam ids, one per line. Applies to both the player and the owner of a family shared game
+    #[structopt(long, parse(from_os_str))]
+    pub ban_list: Option<PathBuf>,
+
+    /// What to do when a steam account connects while already connected: kick-old or reject-new
+    #[structopt(long, default_value = "kick-old")]
+    pub duplicate_login_policy: DuplicateLoginPolicy,
+
+    /// The most players anyone is matched with at once. Clients can ask for fewer
+    #[structopt(long, default_value = "32")]
+    pub max_peers: u32,
+
+    /// Path to a json file with matchmaking profiles (screen height, radius and max peers) per level name
+    #[structopt(long, parse(from_os_str))]
+    pub level_profiles: Option<PathBuf>,
+
+    /// How long used auth tickets are remembered, in seconds. Using one again from another host within this time is rejected
+    #[structopt(long, default_value = "600")]
+    pub ticket_reuse_window: u64,
+
+    /// The oldest protocol version clients are allowed to connect with
+    #[structopt(long, default_value = "3")]
+    pub min_protocol_version: u32,
+
+    /// The newest protocol version the server will negotiate with clients
+    #[structopt(long, default_value = "4")]
+    pub max_protocol_version: u32,
+
+    /// How often clients are pinged, in seconds
+    #[structopt(long, default_value = "10")]
+    pub ping_interval: u64,
+
+    /// How long a client can go without sending anything before it's disconnected, in seconds
+    #[structopt(long, default_value = "30")]
+    pub idle_timeout: u64,
+
+    /// How long a connection can be silent before the os checks if the other end is still there, in seconds.
+    /// The check is repeated at the same interval and the connection dropped after 3 unanswered probes,
+    /// so dead connections of clients too old to answer pings are gone after about four times this
+    #[structopt(long, default_value = "20")]
+    pub tcp_keepalive: u64,
+
+    /// How long a new connection has to send its handshake, in seconds
+    #[structopt(long, default_value = "10")]
+    pub handshake_timeout: u64,
+
+    /// How many connections can be waiting to complete their handshake at once. New connections are dropped while at the limit
+    #[structopt(long, default_value = "256")]
+    pub max_pending_handshakes: usize,
+
+    /// How long to wait for clients to disconnect after telling them the server is shutting down, in seconds.
+    /// Has to be shorter than the kill timeout of the host
+    #[structopt(long, default_value = "3")]
+    pub shutdown_drain: u64,
+
+    /// How long clients are told to wait before reconnecting after a shutdown, in seconds
+    #[structopt(long, default_value = "15")]
+    pub shutdown_reconnect_after: u32,
+}
+
+impl LaunchOptions {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn tcp_keepalive(&self) -> Duration {
+        Duration::from_secs(self.tcp_keepalive)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    pub fn steam_summary_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.steam_summary_cache_ttl)
+    }
+
+    pub fn ticket_reuse_window(&self) -> Duration {
+        Duration::from_secs(self.ticket_reuse_window)
+    }
+
+    pub fn shutdown_drain(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.min_protocol_version > self.max_protocol_version {
+            anyhow::bail!(
+                "Minimum protocol version ({}) is greater than maximum protocol version ({})",
+                self.min_protocol_version,
+                self.max_protocol_version
+            );
+        }
+
+        for version in [self.min_protocol_version, self.max_protocol_version] {
+            if crate::protocol::get_adapter(version).is_none() {
+                anyhow::bail!("Protocol version {} is not supported", version);
+            }
+        }
+
+        if self.ping_interval == 0 || self.ping_interval >= self.idle_timeout {
+            anyhow::bail!(
+                "Ping interval ({}s) has to be above zero and shorter than the idle timeout ({}s)",
+                self.ping_interval,
+                self.idle_timeout
+            );
+        }
+
+        if self.tcp_keepalive == 0 {
+            anyhow::bail!("Tcp keepalive has to be above zero");
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            anyhow::bail!("Both --tls-cert and --tls-key have to be set to enable tls");
+        }
+
+        if self.max_pending_handshakes == 0 {
+            anyhow::bail!("Maximum pending handshakes has to be above zero");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanPolicy {
+    Allow,
+    Reject,
+    Solo,
+}
+
+impl FromStr for BanPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(BanPolicy::Allow),
+            "reject" => Ok(BanPolicy::Reject),
+            "solo" => Ok(BanPolicy::Solo),
+            _ => anyhow::bail!("Unknown ban policy '{}', expected allow, reject or solo", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateLoginPolicy {
+    KickOld,
+    RejectNew,
+}
+
+impl FromStr for DuplicateLoginPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kick-old" => Ok(DuplicateLoginPolicy::KickOld),
+            "reject-new" => Ok(DuplicateLoginPolicy::RejectNew),
+            _ => anyhow::bail!(
+                "Unknown duplicate login policy '{}', expected kick-old or reject-new",
+                s
+            ),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// How players on a level are matched with each other
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct LevelProfile {
+    /// The (unscaled) height in pixels of one screen
+    pub screen_height: i32,
+    /// How many screens above a player others can be and still be matched with them
+    pub radius_up: i32,
+    /// How many screens below a player others can be and still be matched with them
+    pub radius_down: i32,
+    /// The most players anyone is matched with at once. The closest ones are picked when there are more
+    pub max_peers: Option<usize>,
+}
+
+impl Default for LevelProfile {
+    fn default() -> Self {
+        Self {
+            screen_height: 360,
+            radius_up: 3,
+            radius_down: 3,
+            max_peers: None,
+        }
+    }
+}
+
+impl LevelProfile {
+    #[inline]
+    pub fn get_level(&self, y: f32) -> i32 {
+        // y- is up in the game, but we're gonna treat a positive level as up, so we invert the y value
+        let y_i32 = (-y.round()) as i32;
+
+        y_i32 / self.screen_height
+    }
+
+    /// The offsets of the levels in range, nearest first
+    pub fn level_offsets(&self) -> impl Iterator<Item = i32> {
+        let (radius_up, radius_down) = (self.radius_up, self.radius_down);
+
+        std::iter::once(0).chain((1..=radius_up.max(radius_down)).flat_map(move |distance| {
+            let up = Some(distance).filter(|_| distance <= radius_up);
+            let down = Some(-distance).filter(|_| distance <= radius_down);
+            up.into_iter().chain(down)
+        }))
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.screen_height <= 0 {
+            anyhow::bail!("Screen height has to be above zero");
+        }
+
+        if self.radius_up < 0 || self.radius_down < 0 {
+            anyhow::bail!("Radius can't be negative");
+        }
+
+        Ok(())
+    }
+}
+
+/// Matchmaking profiles per level name, for maps that need different settings than the default
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct LevelProfiles {
+    /// Used for levels without a profile of their own
+    default: LevelProfile,
+    levels: HashMap<String, LevelProfile>,
+}
+
+impl LevelProfiles {
+    /// Loads a json file with an optional `default` profile and a `levels` object mapping level names to profiles.
+    /// Settings left out of a profile use the built in defaults
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let profiles: LevelProfiles = serde_json::from_str(&file)
+            .with_context(|| format!("Could not parse level profiles in {}", path.display()))?;
+
+        profiles
+            .default
+            .validate()
+            .context("Invalid default level profile")?;
+
+        for (level_name, profile) in &profiles.levels {
+            profile
+                .validate()
+                .with_context(|| format!("Invalid level profile for {}", level_name))?;
+        }
+
+        Ok(profiles)
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn get(&self, level_name: &str) -> &LevelProfile {
+        self.levels.get(level_name).unwrap_or(&self.default)
+    }
+}
@@ -0,0 +1,66 @@
+use bincode::Options;
+
+use crate::{codec::BincodeOptions, messages::Message};
+
+mod v3;
+
+/// The newest protocol version the server understands.
+pub const VERSION: u32 = 4;
+
+/// The oldest protocol version the server has an adapter for.
+pub const OLDEST_VERSION: u32 = 3;
+
+/// The first protocol version where clients answer `Ping` messages.
+pub const HEARTBEAT_VERSION: u32 = 4;
+
+/// The first protocol version where clients are told when players come into or leave range, instead of getting everyone nearby on every position update.
+pub const NEARBY_DELTAS_VERSION: u32 = 4;
+
+/// Translates between the current message shapes and the shapes used by a specific protocol version.
+pub trait ProtocolAdapter: Send + Sync {
+    /// Serializes the message in this version's shape. Returns `None` if the version has no equivalent of the message.
+    fn serialize(
+        &self,
+        options: BincodeOptions,
+        message: Message,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error>;
+
+    fn deserialize(
+        &self,
+        options: BincodeOptions,
+        payload: &[u8],
+    ) -> Result<Message, anyhow::Error>;
+}
+
+struct CurrentAdapter;
+
+impl ProtocolAdapter for CurrentAdapter {
+    fn serialize(
+        &self,
+        options: BincodeOptions,
+        message: Message,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        Ok(Some(options.serialize(&message)?))
+    }
+
+    fn deserialize(
+        &self,
+        options: BincodeOptions,
+        payload: &[u8],
+    ) -> Result<Message, anyhow::Error> {
+        Ok(options.deserialize(payload)?)
+    }
+}
+
+/// Returns the adapters of every supported version, newest first
+pub fn get_adapters_newest_first() -> impl Iterator<Item = &'static dyn ProtocolAdapter> {
+    (OLDEST_VERSION..=VERSION).rev().filter_map(get_adapter)
+}
+
+pub fn get_adapter(version: u32) -> Option<&'static dyn ProtocolAdapter> {
+    match version {
+        3 => Some(&v3::Adapter),
+        VERSION => Some(&CurrentAdapter),
+        _ => None,
+    }
+}
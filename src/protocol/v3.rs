@@ -0,0 +1,159 @@
+//! Protocol version 3, used by mod releases before version negotiation was added.
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::ProtocolAdapter;
+use crate::{chat::ChatChannel, codec::BincodeOptions, math::Vector2, messages};
+
+pub struct Adapter;
+
+impl ProtocolAdapter for Adapter {
+    fn serialize(
+        &self,
+        options: BincodeOptions,
+        message: messages::Message,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match Message::downgrade(message) {
+            Some(message) => Ok(Some(options.serialize(&message)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn deserialize(
+        &self,
+        options: BincodeOptions,
+        payload: &[u8],
+    ) -> Result<messages::Message, anyhow::Error> {
+        let message: Message = options.deserialize(payload)?;
+        Ok(message.upgrade())
+    }
+}
+
+// Variant order has to match the version 3 enum exactly since bincode encodes the variant index
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    HandshakeRequest(HandshakeRequest),
+    HandshakeResponse(HandshakeResponse),
+    PositionUpdate(messages::PositionUpdate),
+    SetMatchmakingPassword(messages::SetMatchmakingPassword),
+    InformNearbyClients(messages::InformNearbyClients),
+    IncomingChatMessage(messages::IncomingChatMessage),
+    OutgoingChatMessage(messages::OutgoingChatMessage),
+    ServerStatusUpdate(messages::ServerStatusUpdate),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HandshakeRequest {
+    auth_session_ticket: Vec<u8>,
+    matchmaking_password: Option<String>,
+    level_name: String,
+    position: Vector2,
+    version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HandshakeResponse {
+    success: bool,
+    error_message: Option<String>,
+}
+
+impl Message {
+    fn upgrade(self) -> messages::Message {
+        match self {
+            Message::HandshakeRequest(val) => {
+                messages::Message::HandshakeRequest(messages::HandshakeRequest {
+                    auth_session_ticket: val.auth_session_ticket,
+                    matchmaking_password: val.matchmaking_password,
+                    level_name: val.level_name,
+                    position: val.position,
+                    version: val.version,
+                    max_peers: None,
+                })
+            }
+            Message::HandshakeResponse(val) => {
+                messages::Message::HandshakeResponse(messages::HandshakeResponse {
+                    success: val.success,
+                    error_message: val.error_message,
+                    negotiated_version: 3,
+                    min_supported_version: 3,
+                    error_code: None,
+                })
+            }
+            Message::PositionUpdate(val) => messages::Message::PositionUpdate(val),
+            Message::SetMatchmakingPassword(val) => messages::Message::SetMatchmakingPassword(val),
+            Message::InformNearbyClients(val) => messages::Message::InformNearbyClients(val),
+            Message::IncomingChatMessage(val) => messages::Message::IncomingChatMessage(val),
+            Message::OutgoingChatMessage(val) => messages::Message::OutgoingChatMessage(val),
+            Message::ServerStatusUpdate(val) => messages::Message::ServerStatusUpdate(val),
+        }
+    }
+
+    fn downgrade(message: messages::Message) -> Option<Self> {
+        let message = match message {
+            messages::Message::HandshakeRequest(val) => {
+                Message::HandshakeRequest(HandshakeRequest {
+                    auth_session_ticket: val.auth_session_ticket,
+                    matchmaking_password: val.matchmaking_password,
+                    level_name: val.level_name,
+                    position: val.position,
+                    version: val.version,
+                })
+            }
+            messages::Message::HandshakeResponse(val) => {
+                Message::HandshakeResponse(HandshakeResponse {
+                    success: val.success,
+                    error_message: val.error_message,
+                })
+            }
+            messages::Message::PositionUpdate(val) => Message::PositionUpdate(val),
+            messages::Message::SetMatchmakingPassword(val) => Message::SetMatchmakingPassword(val),
+            messages::Message::InformNearbyClients(val) => Message::InformNearbyClients(val),
+            messages::Message::IncomingChatMessage(val) => Message::IncomingChatMessage(val),
+            messages::Message::OutgoingChatMessage(val) => Message::OutgoingChatMessage(val),
+            messages::Message::ServerStatusUpdate(val) => Message::ServerStatusUpdate(val),
+            // Version 3 clients only know the full list, but adding to it is the closest they get to a delta
+            messages::Message::NearbyClientsAdded(val) => {
+                Message::InformNearbyClients(messages::InformNearbyClients {
+                    client_ids: val.client_ids,
+                })
+            }
+            messages::Message::Ping(_)
+            | messages::Message::Pong(_)
+            | messages::Message::PlayerNameChanged(_)
+            | messages::Message::NearbyClientsRemoved(_)
+            | messages::Message::SetMatchmakingOptions(_) => return None,
+            // Version 3 clients only know about chat, so tell them through a system message instead
+            messages::Message::ServerShutdown(val) => {
+                Message::OutgoingChatMessage(messages::OutgoingChatMessage {
+                    channel: ChatChannel::Global,
+                    sender_id: None,
+                    sender_name: None,
+                    message: format!(
+                        "The server is shutting down ({}). Try reconnecting in {} seconds.",
+                        val.reason, val.reconnect_after_secs
+                    ),
+                })
+            }
+            messages::Message::Kicked(val) => {
+                Message::OutgoingChatMessage(messages::OutgoingChatMessage {
+                    channel: ChatChannel::Global,
+                    sender_id: None,
+                    sender_name: None,
+                    message: format!("You have been disconnected: {}", val.reason),
+                })
+            }
+            messages::Message::ServerError(val) => {
+                Message::OutgoingChatMessage(messages::OutgoingChatMessage {
+                    channel: ChatChannel::Global,
+                    sender_id: None,
+                    sender_name: None,
+                    message: val.message,
+                })
+            }
+        };
+
+        Some(message)
+    }
+}
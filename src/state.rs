@@ -1,29 +1,68 @@
 use std::{
+    cmp::Ordering,
     collections::{
         hash_map::{Iter, IterMut},
-        HashMap,
+        HashMap, HashSet,
     },
     hash::Hash,
     net::SocketAddr,
+    sync::Arc,
 };
 
-use crate::{client::Client, math::Vector2};
+use crate::{
+    bans::BanList,
+    client::Client,
+    math::Vector2,
+    messages::{Message, NearbyClientsAdded, NearbyClientsRemoved},
+    profiles::{LevelProfile, LevelProfiles},
+    steam::SteamApi,
+    tickets::UsedTickets,
+    util::networking::MAX_CLIENT_IDS_PER_MESSAGE,
+};
 
 pub struct State {
     clients: HashMap<SocketAddr, Client>,
-    matchmaking_map: HashMap<MatchmakingOptions, Vec<SocketAddr>>,
+    matchmaking_map: HashMap<MatchmakingOptions, Group>,
     client_matchmaking_map: HashMap<SocketAddr, MatchmakingOptions>,
+    /// The clients each client is matched with. Always symmetric
+    nearby_map: HashMap<SocketAddr, HashSet<SocketAddr>>,
+    steam: Arc<dyn SteamApi>,
+    ban_list: BanList,
+    used_tickets: UsedTickets,
+    level_profiles: LevelProfiles,
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(
+        steam: Arc<dyn SteamApi>,
+        ban_list: BanList,
+        used_tickets: UsedTickets,
+        level_profiles: LevelProfiles,
+    ) -> Self {
         Self {
             clients: HashMap::new(),
             matchmaking_map: HashMap::new(),
             client_matchmaking_map: HashMap::new(),
+            nearby_map: HashMap::new(),
+            steam,
+            ban_list,
+            used_tickets,
+            level_profiles,
         }
     }
 
+    pub fn steam(&self) -> Arc<dyn SteamApi> {
+        self.steam.clone()
+    }
+
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
+    }
+
+    pub fn used_tickets_mut(&mut self) -> &mut UsedTickets {
+        &mut self.used_tickets
+    }
+
     pub fn add_client(
         &mut self,
         address: &SocketAddr,
@@ -40,13 +79,13 @@ impl State {
     }
 
     pub fn remove_client(&mut self, address: &SocketAddr) -> Option<Client> {
-        match self.clients.remove(address) {
-            Some(client) => {
-                self.set_matchmaking_options(address, None);
-                Some(client)
-            }
-            None => None,
+        if !self.clients.contains_key(address) {
+            return None;
         }
+
+        // Leave the group before removing the client, so the players near it can still be told who left
+        self.set_matchmaking_options(address, None);
+        self.clients.remove(address)
     }
 
     pub fn get_client(&self, address: &SocketAddr) -> Option<&Client> {
@@ -57,11 +96,35 @@ impl State {
         self.clients.get_mut(address)
     }
 
-    pub fn get_clients_iter(&self) -> Iter<SocketAddr, Client> {
+    /// Moves the client and keeps its group's level buckets up to date.
+    /// Positions have to be changed through here rather than on the client directly.
+    pub fn update_position(&mut self, address: &SocketAddr, position: Vector2) -> Option<&Client> {
+        self.get_client_mut(address)?.position = position;
+
+        if let Some(options) = self.client_matchmaking_map.get(address) {
+            if let Some(group) = self.matchmaking_map.get_mut(options) {
+                group.set_level(address, group.profile.get_level(position.y));
+            }
+        }
+
+        self.update_nearby(address);
+
+        self.clients.get(address)
+    }
+
+    /// Finds the address of the session a steam account is connected with, if any
+    pub fn find_client_by_steam_id(&self, steam_id: u64) -> Option<SocketAddr> {
+        self.clients
+            .iter()
+            .find(|(_, client)| client.steam_id == steam_id)
+            .map(|(address, _)| *address)
+    }
+
+    pub fn get_clients_iter(&self) -> Iter<'_, SocketAddr, Client> {
         self.clients.iter()
     }
 
-    pub fn get_clients_iter_mut(&mut self) -> IterMut<SocketAddr, Client> {
+    pub fn get_clients_iter_mut(&mut self) -> IterMut<'_, SocketAddr, Client> {
         self.clients.iter_mut()
     }
 
@@ -72,32 +135,197 @@ impl State {
         self.matchmaking_map
             .get(matchmaking_options)
             .into_iter()
-            .flat_map(move |group| group.iter().filter_map(move |addr| self.clients.get(addr)))
+            .flat_map(move |group| {
+                group
+                    .members()
+                    .filter_map(move |addr| self.clients.get(addr))
+            })
     }
 
-    pub fn get_nearby_clients(
-        &self,
-        position: &Vector2,
-        matchmaking_options: &MatchmakingOptions,
-    ) -> Vec<&Client> {
-        let mut result = Vec::<&Client>::new();
-        let level = get_y_level(position.y);
-        let clients = self.get_clients_in_group(matchmaking_options);
+    /// Returns the clients the client is currently matched with
+    pub fn get_nearby_clients(&self, address: &SocketAddr) -> Vec<&Client> {
+        self.nearby_map
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| self.clients.get(addr))
+            .collect()
+    }
+
+    /// Returns the other clients in the group that are close enough to matchmake with, closest first
+    fn get_clients_in_range(&self, address: &SocketAddr) -> Vec<SocketAddr> {
+        let client = self.clients.get(address);
+        let group = self
+            .client_matchmaking_map
+            .get(address)
+            .and_then(|matchmaking_options| self.matchmaking_map.get(matchmaking_options));
+
+        let (client, group) = match (client, group) {
+            (Some(client), Some(group)) => (client, group),
+            _ => return Vec::new(),
+        };
+
+        let profile = &group.profile;
+        let level = profile.get_level(client.position.y);
+
+        let mut in_range = profile
+            .level_offsets()
+            .flat_map(|offset| group.members_on_level(level + offset))
+            .filter(|addr| *addr != address)
+            .filter_map(|addr| {
+                let other = self.clients.get(addr)?;
+                Some((*addr, client.position.distance_squared(&other.position)))
+            })
+            .collect::<Vec<_>>();
 
-        for other in clients {
-            let other_level = get_y_level(other.position.y);
+        in_range.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        in_range.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// The most players the client can be matched with, from its own request and its level profile
+    fn get_peer_limit(&self, address: &SocketAddr) -> usize {
+        let client_limit = self
+            .clients
+            .get(address)
+            .map_or(0, |client| client.max_peers);
+        let profile_limit = self
+            .client_matchmaking_map
+            .get(address)
+            .and_then(|matchmaking_options| self.matchmaking_map.get(matchmaking_options))
+            .and_then(|group| group.profile.max_peers)
+            .unwrap_or(usize::MAX);
+
+        client_limit.min(profile_limit)
+    }
+
+    /// Works out who the client is matched with now and tells both sides about anyone that came into or left range.
+    /// Pairs are kept symmetric, so a player is only picked if they have room for another peer or are already matched with the client.
+    fn update_nearby(&mut self, address: &SocketAddr) {
+        let previous = self.nearby_map.remove(address).unwrap_or_default();
+        let current = self
+            .get_clients_in_range(address)
+            .into_iter()
+            .filter(|other| {
+                previous.contains(other)
+                    || self.nearby_map.get(other).map_or(0, HashSet::len)
+                        < self.get_peer_limit(other)
+            })
+            .take(self.get_peer_limit(address))
+            .collect::<HashSet<_>>();
+
+        let added = current.difference(&previous).copied().collect::<Vec<_>>();
+        let removed = previous.difference(&current).copied().collect::<Vec<_>>();
 
-            // If the players are within 3 screens from eachother, they are close enough to matchmake
-            if (level - other_level).abs() <= 3 {
-                result.push(other);
+        for other in &added {
+            self.nearby_map.entry(*other).or_default().insert(*address);
+        }
+
+        for other in &removed {
+            if let Some(other_nearby) = self.nearby_map.get_mut(other) {
+                other_nearby.remove(address);
+
+                if other_nearby.is_empty() {
+                    self.nearby_map.remove(other);
+                }
             }
         }
 
-        result
+        if !current.is_empty() {
+            self.nearby_map.insert(*address, current);
+        }
+
+        self.send_nearby_changes(address, &added, &removed);
+
+        // The peers the client left have a free slot now, which someone else in their range may be waiting for
+        for other in &removed {
+            self.fill_nearby(other);
+        }
     }
 
-    pub fn get_matchmaking_options(&self, address: &SocketAddr) -> &MatchmakingOptions {
-        self.client_matchmaking_map.get(address).unwrap()
+    /// Matches the client with the closest players in range that have room, until it's at its limit.
+    /// Never drops a peer, so filling one client can't free up slots that others would have to refill in turn.
+    fn fill_nearby(&mut self, address: &SocketAddr) {
+        let current = self.nearby_map.get(address).cloned().unwrap_or_default();
+        let free_slots = self.get_peer_limit(address).saturating_sub(current.len());
+
+        if free_slots == 0 {
+            return;
+        }
+
+        let added = self
+            .get_clients_in_range(address)
+            .into_iter()
+            .filter(|other| {
+                !current.contains(other)
+                    && self.nearby_map.get(other).map_or(0, HashSet::len)
+                        < self.get_peer_limit(other)
+            })
+            .take(free_slots)
+            .collect::<Vec<_>>();
+
+        if added.is_empty() {
+            return;
+        }
+
+        for other in &added {
+            self.nearby_map.entry(*other).or_default().insert(*address);
+        }
+
+        self.nearby_map
+            .entry(*address)
+            .or_default()
+            .extend(added.iter().copied());
+
+        self.send_nearby_changes(address, &added, &[]);
+    }
+
+    fn send_nearby_changes(
+        &self,
+        address: &SocketAddr,
+        added: &[SocketAddr],
+        removed: &[SocketAddr],
+    ) {
+        let client = match self.clients.get(address) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let steam_ids = |addresses: &[SocketAddr]| {
+            addresses
+                .iter()
+                .filter_map(|addr| self.clients.get(addr))
+                .map(|other| other.steam_id)
+                .collect::<Vec<_>>()
+        };
+
+        // Ignore failed sends, the clients are removed from the state when their connection closes
+        for chunk in steam_ids(added).chunks(MAX_CLIENT_IDS_PER_MESSAGE) {
+            let _ = client.send(Message::NearbyClientsAdded(NearbyClientsAdded {
+                client_ids: chunk.into(),
+            }));
+        }
+
+        for chunk in steam_ids(removed).chunks(MAX_CLIENT_IDS_PER_MESSAGE) {
+            let _ = client.send(Message::NearbyClientsRemoved(NearbyClientsRemoved {
+                client_ids: chunk.into(),
+            }));
+        }
+
+        for other in added.iter().filter_map(|addr| self.clients.get(addr)) {
+            let _ = other.send(Message::NearbyClientsAdded(NearbyClientsAdded {
+                client_ids: vec![client.steam_id],
+            }));
+        }
+
+        for other in removed.iter().filter_map(|addr| self.clients.get(addr)) {
+            let _ = other.send(Message::NearbyClientsRemoved(NearbyClientsRemoved {
+                client_ids: vec![client.steam_id],
+            }));
+        }
+    }
+
+    pub fn get_matchmaking_options(&self, address: &SocketAddr) -> Option<&MatchmakingOptions> {
+        self.client_matchmaking_map.get(address)
     }
 
     pub fn set_matchmaking_options(
@@ -118,7 +346,7 @@ impl State {
 
             {
                 let group = self.matchmaking_map.get_mut(previous_options).unwrap();
-                group.retain(|addr| addr != address);
+                group.remove(address);
                 new_group_len = group.len();
             }
 
@@ -136,12 +364,19 @@ impl State {
         match matchmaking_options {
             Some(matchmaking_options) => {
                 // Add to new group if new matchmaking options is set
+                let profile = *self.level_profiles.get(&matchmaking_options.level_name);
                 let group = self
                     .matchmaking_map
                     .entry(matchmaking_options.clone())
-                    .or_insert_with(Vec::<SocketAddr>::new);
+                    .or_insert_with(|| Group::new(profile));
 
-                group.push(*address);
+                // Clients are always added to the state before joining a group, but don't panic if that changes
+                let level = self
+                    .clients
+                    .get(address)
+                    .map(|client| profile.get_level(client.position.y))
+                    .unwrap_or_default();
+                group.insert(address, level);
 
                 tracing::info!(
                     "Added {} to group. Members in group is now {}",
@@ -158,23 +393,96 @@ impl State {
                 self.client_matchmaking_map.remove(address);
             }
         }
+
+        self.update_nearby(address);
     }
 }
 
-#[inline]
-fn get_y_level(y: f32) -> i32 {
-    const Y_LEVEL_HEIGHT: i32 = 360; // 360 is the (unscaled) height in pixels of the screen at any time
+#[cfg(test)]
+impl State {
+    /// A state with the default settings whose steam api only knows the given users
+    pub fn for_tests(users: Vec<crate::steam::MockUser>) -> Self {
+        Self::new(
+            Arc::new(crate::steam::MockSteamApi::new(users)),
+            BanList::default(),
+            UsedTickets::new(std::time::Duration::from_secs(60)),
+            LevelProfiles::default(),
+        )
+    }
+}
 
-    // y- is up in the game, but we're gonna treat a positive level as up, so we invert the y value
-    let y_i32 = (-y.round()) as i32;
+/// The members of a matchmaking group, bucketed by the screen level they're on so nearby lookups only have to look at the few levels around a player
+struct Group {
+    profile: LevelProfile,
+    levels: HashMap<i32, Vec<SocketAddr>>,
+    member_levels: HashMap<SocketAddr, i32>,
+}
 
-    y_i32 / Y_LEVEL_HEIGHT
+impl Group {
+    fn new(profile: LevelProfile) -> Self {
+        Self {
+            profile,
+            levels: HashMap::new(),
+            member_levels: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.member_levels.len()
+    }
+
+    fn members(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.member_levels.keys()
+    }
+
+    fn members_on_level(&self, level: i32) -> impl Iterator<Item = &SocketAddr> {
+        self.levels.get(&level).into_iter().flatten()
+    }
+
+    fn insert(&mut self, address: &SocketAddr, level: i32) {
+        if self.member_levels.contains_key(address) {
+            self.set_level(address, level);
+        } else {
+            self.member_levels.insert(*address, level);
+            self.levels.entry(level).or_default().push(*address);
+        }
+    }
+
+    fn remove(&mut self, address: &SocketAddr) {
+        if let Some(level) = self.member_levels.remove(address) {
+            self.remove_from_level(address, level);
+        }
+    }
+
+    fn set_level(&mut self, address: &SocketAddr, level: i32) {
+        let previous_level = match self.member_levels.get_mut(address) {
+            Some(previous_level) if *previous_level != level => {
+                std::mem::replace(previous_level, level)
+            }
+            _ => return,
+        };
+
+        self.remove_from_level(address, previous_level);
+        self.levels.entry(level).or_default().push(*address);
+    }
+
+    fn remove_from_level(&mut self, address: &SocketAddr, level: i32) {
+        if let Some(bucket) = self.levels.get_mut(&level) {
+            bucket.retain(|addr| addr != address);
+
+            if bucket.is_empty() {
+                self.levels.remove(&level);
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct MatchmakingOptions {
     pub password: Option<String>,
     pub level_name: String,
+    /// Set for players restricted to solo play, which puts them in a group of their own
+    pub isolated_steam_id: Option<u64>,
 }
 
 impl MatchmakingOptions {
@@ -182,6 +490,432 @@ impl MatchmakingOptions {
         Self {
             password,
             level_name,
+            isolated_steam_id: None,
+        }
+    }
+
+    pub fn isolated(self, steam_id: u64) -> Self {
+        Self {
+            isolated_steam_id: Some(steam_id),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Checks that the level buckets and the member levels describe the same members
+    fn assert_consistent(group: &Group) {
+        let bucketed = group.levels.values().map(Vec::len).sum::<usize>();
+        assert_eq!(bucketed, group.len());
+
+        for (address, level) in &group.member_levels {
+            let on_level = group
+                .members_on_level(*level)
+                .filter(|addr| *addr == address)
+                .count();
+            assert_eq!(on_level, 1, "{} should be on level {} once", address, level);
+        }
+
+        assert!(group.levels.values().all(|bucket| !bucket.is_empty()));
+    }
+
+    #[test]
+    fn group_insert() {
+        let mut group = Group::new(LevelProfile::default());
+
+        group.insert(&address(1), 0);
+        group.insert(&address(2), 0);
+        group.insert(&address(3), 5);
+        // Inserting again moves the member instead of adding it twice
+        group.insert(&address(1), 5);
+
+        assert_eq!(group.len(), 3);
+        assert_eq!(group.members_on_level(0).collect::<Vec<_>>(), [&address(2)]);
+        assert_eq!(group.members_on_level(5).count(), 2);
+        assert_consistent(&group);
+    }
+
+    #[test]
+    fn group_set_level() {
+        let mut group = Group::new(LevelProfile::default());
+        group.insert(&address(1), 0);
+        group.insert(&address(2), 0);
+
+        group.set_level(&address(1), -2);
+        group.set_level(&address(2), 0);
+        // Not a member, so it's ignored
+        group.set_level(&address(3), 1);
+
+        assert_eq!(group.len(), 2);
+        assert_eq!(group.members_on_level(0).collect::<Vec<_>>(), [&address(2)]);
+        assert_eq!(
+            group.members_on_level(-2).collect::<Vec<_>>(),
+            [&address(1)]
+        );
+        assert_eq!(group.members_on_level(1).count(), 0);
+        assert_consistent(&group);
+
+        group.set_level(&address(2), -2);
+
+        assert!(!group.levels.contains_key(&0));
+        assert_eq!(group.members_on_level(-2).count(), 2);
+        assert_consistent(&group);
+    }
+
+    #[test]
+    fn group_remove() {
+        let mut group = Group::new(LevelProfile::default());
+        group.insert(&address(1), 0);
+        group.insert(&address(2), 0);
+        group.insert(&address(3), 1);
+
+        group.remove(&address(1));
+        group.remove(&address(3));
+        group.remove(&address(4));
+
+        assert_eq!(group.len(), 1);
+        assert_eq!(group.members().collect::<Vec<_>>(), [&address(2)]);
+        assert!(!group.levels.contains_key(&1));
+        assert_consistent(&group);
+    }
+
+    fn create_state(clients: u16, screens: u16) -> State {
+        let mut state = State::for_tests(Vec::new());
+        let screen_height = LevelProfile::default().screen_height as f32;
+
+        for port in 0..clients {
+            let (tx, _) = mpsc::unbounded_channel();
+            // Spread the clients evenly over the screens, a bit below the top of each one
+            let y = -f32::from(port % screens) * screen_height - 10.0;
+            let client = Client::new(
+                tx,
+                port.into(),
+                port.into(),
+                port.to_string(),
+                Vector2 { x: 0.0, y },
+                usize::MAX,
+            );
+            state.add_client(
+                &address(port),
+                client,
+                MatchmakingOptions::new(None, "level".to_string()),
+            );
+        }
+
+        state
+    }
+
+    /// Adds a client to the same group as every other test client and returns what it's sent
+    fn join(
+        state: &mut State,
+        port: u16,
+        position: Vector2,
+        max_peers: usize,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let client = Client::new(
+            tx,
+            port.into(),
+            port.into(),
+            port.to_string(),
+            position,
+            max_peers,
+        );
+        state.add_client(
+            &address(port),
+            client,
+            MatchmakingOptions::new(None, "level".to_string()),
+        );
+        rx
+    }
+
+    fn nearby_ports(state: &State, port: u16) -> Vec<u64> {
+        let mut ports = state
+            .get_nearby_clients(&address(port))
+            .iter()
+            .map(|client| client.steam_id)
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Clients 1 and 2 are matched, and 3 is in range of both but they have no room for it
+    fn create_full_pair() -> (State, Vec<mpsc::UnboundedReceiver<Message>>) {
+        let mut state = State::for_tests(Vec::new());
+        let receivers = (1..=3)
+            .map(|port| {
+                let position = Vector2 {
+                    x: f32::from(port) * 10.0,
+                    y: 0.0,
+                };
+                join(&mut state, port, position, 1)
+            })
+            .collect();
+
+        assert_eq!(nearby_ports(&state, 1), [2]);
+        assert!(nearby_ports(&state, 3).is_empty());
+
+        (state, receivers)
+    }
+
+    #[test]
+    fn disconnecting_frees_slots_for_others() {
+        let (mut state, _receivers) = create_full_pair();
+
+        state.remove_client(&address(2));
+
+        assert_eq!(nearby_ports(&state, 1), [3]);
+        assert_eq!(nearby_ports(&state, 3), [1]);
+    }
+
+    #[test]
+    fn moving_away_frees_slots_for_others() {
+        let (mut state, _receivers) = create_full_pair();
+
+        state.update_position(
+            &address(2),
+            Vector2 {
+                x: 20.0,
+                y: -100_000.0,
+            },
+        );
+
+        assert!(nearby_ports(&state, 2).is_empty());
+        assert_eq!(nearby_ports(&state, 1), [3]);
+        assert_eq!(nearby_ports(&state, 3), [1]);
+    }
+
+    /// Takes the nearby deltas the client was sent so far, as the ids added and the ids removed
+    fn drain_deltas(rx: &mut mpsc::UnboundedReceiver<Message>) -> (Vec<u64>, Vec<u64>) {
+        let (mut added, mut removed) = (Vec::new(), Vec::new());
+
+        while let Ok(message) = rx.try_recv() {
+            match message {
+                Message::NearbyClientsAdded(message) => added.extend(message.client_ids),
+                Message::NearbyClientsRemoved(message) => removed.extend(message.client_ids),
+                _ => {}
+            }
         }
+
+        added.sort_unstable();
+        removed.sort_unstable();
+        (added, removed)
+    }
+
+    #[test]
+    fn moving_sends_deltas_to_both_sides() {
+        let mut state = State::for_tests(Vec::new());
+        let mut rx1 = join(&mut state, 1, Vector2 { x: 0.0, y: 0.0 }, usize::MAX);
+        let mut rx2 = join(&mut state, 2, Vector2 { x: 0.0, y: -5000.0 }, usize::MAX);
+
+        assert_eq!(drain_deltas(&mut rx1), (vec![], vec![]));
+        assert_eq!(drain_deltas(&mut rx2), (vec![], vec![]));
+
+        state.update_position(&address(2), Vector2 { x: 10.0, y: 0.0 });
+
+        assert_eq!(drain_deltas(&mut rx1), (vec![2], vec![]));
+        assert_eq!(drain_deltas(&mut rx2), (vec![1], vec![]));
+
+        // Moving within range changes nothing
+        state.update_position(&address(2), Vector2 { x: 20.0, y: -10.0 });
+
+        assert_eq!(drain_deltas(&mut rx1), (vec![], vec![]));
+        assert_eq!(drain_deltas(&mut rx2), (vec![], vec![]));
+
+        state.update_position(&address(1), Vector2 { x: 0.0, y: -5000.0 });
+
+        assert_eq!(drain_deltas(&mut rx1), (vec![], vec![2]));
+        assert_eq!(drain_deltas(&mut rx2), (vec![], vec![1]));
+    }
+
+    #[test]
+    fn removing_a_client_sends_removed_deltas() {
+        let mut state = State::for_tests(Vec::new());
+        let mut receivers = (1..=3)
+            .map(|port| join(&mut state, port, Vector2 { x: 0.0, y: 0.0 }, usize::MAX))
+            .collect::<Vec<_>>();
+        for rx in &mut receivers {
+            drain_deltas(rx);
+        }
+
+        state.remove_client(&address(2));
+
+        assert_eq!(drain_deltas(&mut receivers[0]), (vec![], vec![2]));
+        assert_eq!(drain_deltas(&mut receivers[2]), (vec![], vec![2]));
+        assert_eq!(nearby_ports(&state, 1), [3]);
+        assert_eq!(nearby_ports(&state, 3), [1]);
+        assert!(!state.nearby_map.contains_key(&address(2)));
+    }
+
+    /// Checks that A is matched with B exactly when B is matched with A, and that nobody is over their limit
+    fn assert_symmetric(state: &State) {
+        for (address, nearby) in &state.nearby_map {
+            assert!(!nearby.is_empty(), "{} has an empty nearby set", address);
+            assert!(
+                !nearby.contains(address),
+                "{} is matched with itself",
+                address
+            );
+            assert!(
+                nearby.len() <= state.get_peer_limit(address),
+                "{} is over its peer limit",
+                address
+            );
+
+            for other in nearby {
+                assert!(
+                    state.nearby_map[other].contains(address),
+                    "{} sees {} but not the other way around",
+                    address,
+                    other
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearby_stays_symmetric_under_caps() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut state = State::for_tests(Vec::new());
+        let mut receivers = HashMap::new();
+        // What each client was told it's matched with, from the deltas alone
+        let mut seen = HashMap::<u16, HashSet<u64>>::new();
+
+        let random_position = |rng: &mut StdRng| Vector2 {
+            x: rng.gen_range(-500.0..500.0),
+            y: rng.gen_range(-3000.0..0.0),
+        };
+
+        for step in 0..500 {
+            let port = rng.gen_range(0..40);
+
+            match (receivers.contains_key(&port), rng.gen_range(0..10)) {
+                (false, _) => {
+                    let max_peers = rng.gen_range(1..5);
+                    let position = random_position(&mut rng);
+                    receivers.insert(port, join(&mut state, port, position, max_peers));
+                }
+                (true, 0) => {
+                    state.remove_client(&address(port));
+                    receivers.remove(&port);
+                    seen.remove(&port);
+                }
+                (true, _) => {
+                    let position = random_position(&mut rng);
+                    state.update_position(&address(port), position);
+                }
+            }
+
+            assert_symmetric(&state);
+
+            for (port, rx) in &mut receivers {
+                let (added, removed) = drain_deltas(rx);
+                let seen = seen.entry(*port).or_default();
+                seen.extend(added);
+                for id in removed {
+                    seen.remove(&id);
+                }
+
+                let mut seen = seen.iter().copied().collect::<Vec<_>>();
+                seen.sort_unstable();
+                assert_eq!(seen, nearby_ports(&state, *port), "step {}", step);
+            }
+        }
+
+        // Make sure the caps were actually in play
+        assert!(state
+            .nearby_map
+            .iter()
+            .any(|(address, nearby)| nearby.len() == state.get_peer_limit(address)));
+    }
+
+    /// What `get_clients_in_range` did before the level buckets: check every member of the group
+    fn get_clients_in_range_linear(state: &State, address: &SocketAddr) -> Vec<SocketAddr> {
+        let client = &state.clients[address];
+        let group = &state.matchmaking_map[&state.client_matchmaking_map[address]];
+        let profile = &group.profile;
+        let level = profile.get_level(client.position.y);
+
+        let mut in_range = group
+            .members()
+            .filter(|addr| *addr != address)
+            .filter_map(|addr| {
+                let other = &state.clients[addr];
+                let offset = profile.get_level(other.position.y) - level;
+                if offset > profile.radius_up || offset < -profile.radius_down {
+                    return None;
+                }
+                Some((*addr, client.position.distance_squared(&other.position)))
+            })
+            .collect::<Vec<_>>();
+
+        in_range.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        in_range.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    #[test]
+    fn update_position_moves_between_buckets() {
+        let mut state = create_state(10, 10);
+        let options = MatchmakingOptions::new(None, "level".to_string());
+
+        state.update_position(&address(0), Vector2 { x: 0.0, y: -3000.0 });
+
+        let group = &state.matchmaking_map[&options];
+        let level = group.profile.get_level(-3000.0);
+        assert!(group
+            .members_on_level(level)
+            .any(|addr| *addr == address(0)));
+        assert!(group.members_on_level(0).all(|addr| *addr != address(0)));
+        assert_consistent(group);
+    }
+
+    fn sorted(mut addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        addresses.sort();
+        addresses
+    }
+
+    #[test]
+    fn bucketed_lookup_matches_linear_scan() {
+        let state = create_state(2_000, 400);
+
+        for address in (0..2_000).step_by(25).map(address) {
+            assert_eq!(
+                sorted(state.get_clients_in_range(&address)),
+                sorted(get_clients_in_range_linear(&state, &address))
+            );
+        }
+    }
+
+    /// Runs the lookups the benchmarks time, so both do the same work
+    fn run_lookups(lookup: impl Fn(&State, &SocketAddr) -> Vec<SocketAddr>) {
+        let state = create_state(10_000, 2_000);
+
+        for address in (0..10_000).step_by(50).map(address) {
+            std::hint::black_box(lookup(&state, &address));
+        }
+    }
+
+    // Compare the time these take with `cargo test --release -- --ignored --exact <test name>`
+
+    #[test]
+    #[ignore]
+    fn benchmark_bucketed_lookup() {
+        run_lookups(State::get_clients_in_range);
+    }
+
+    #[test]
+    #[ignore]
+    fn benchmark_linear_scan() {
+        run_lookups(get_clients_in_range_linear);
     }
 }
@@ -0,0 +1,162 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::SteamUnavailable;
+
+/// Stops calling an api that keeps failing, so players get an answer right away instead of waiting for every retry to time out.
+///
+/// After `failure_threshold` failed calls in a row the breaker opens and rejects all calls for `open_duration`.
+/// After that a single probe call is let through. Its success closes the breaker, and its failure reopens it right away.
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe call is in flight. If it never reports back, another one is let through after `open_duration`
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// How long callers are told to wait while a probe call is deciding whether the api is back
+const PROBE_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            failure_threshold,
+            open_duration,
+        }
+    }
+
+    /// Returns an error if calls should not be attempted right now
+    pub fn check(&self) -> Result<(), SteamUnavailable> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        match *state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if now < until => Err(SteamUnavailable {
+                retry_after: until - now,
+            }),
+            BreakerState::HalfOpen { since } if now < since + self.open_duration => {
+                Err(SteamUnavailable {
+                    retry_after: PROBE_RETRY_AFTER,
+                })
+            }
+            // This caller is the probe
+            _ => {
+                *state = BreakerState::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::Closed { failures: 0 };
+    }
+
+    /// Records a failed call and returns how long callers should wait before trying again
+    pub fn record_failure(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            _ => self.failure_threshold,
+        };
+
+        if failures >= self.failure_threshold {
+            if !matches!(*state, BreakerState::Open { .. }) {
+                tracing::warn!(
+                    "Steam api failed {} times in a row, not calling it for {:?}",
+                    failures,
+                    self.open_duration
+                );
+            }
+
+            *state = BreakerState::Open {
+                until: Instant::now() + self.open_duration,
+            };
+            self.open_duration
+        } else {
+            *state = BreakerState::Closed { failures };
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(10));
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(breaker.check().is_ok());
+        breaker.record_failure();
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn lost_probe_is_replaced() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+
+        // The probe never reports back, like a handshake that timed out mid request
+        assert!(breaker.check().is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{PlayerSummary, SteamApi, SteamAuthError, UserSteamId};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Accepts self made tickets instead of real steam tickets, so several fake players can run on one machine.
+///
+/// A ticket is the utf-8 string `{steam_id}:{name}:{signature}`, where the signature is the hex encoded
+/// HMAC-SHA256 of `{steam_id}:{name}` using the shared secret.
+pub struct DevAuthSteamApi {
+    secret: String,
+    /// Names from verified tickets, since there's no steam profile to look them up from
+    names: Mutex<HashMap<u64, String>>,
+}
+
+impl DevAuthSteamApi {
+    pub fn new(secret: String) -> Self {
+        Self {
+            secret,
+            names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn verify(&self, ticket: &[u8]) -> Result<UserSteamId, anyhow::Error> {
+        let ticket = std::str::from_utf8(ticket)
+            .map_err(|_| invalid_ticket("Dev auth ticket is not utf-8"))?;
+
+        let (payload, signature) = ticket
+            .rsplit_once(':')
+            .ok_or_else(|| invalid_ticket("Dev auth ticket is missing a signature"))?;
+        let (steam_id, name) = payload
+            .split_once(':')
+            .ok_or_else(|| invalid_ticket("Dev auth ticket is missing a name"))?;
+        let signature = hex::decode(signature)
+            .map_err(|_| invalid_ticket("Dev auth ticket signature is not hex"))?;
+
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| invalid_ticket("Dev auth ticket has an invalid signature"))?;
+
+        let steam_id = steam_id
+            .parse::<u64>()
+            .map_err(|_| invalid_ticket("Dev auth ticket steam id is not a number"))?;
+        self.names
+            .lock()
+            .unwrap()
+            .insert(steam_id, name.to_string());
+
+        Ok(UserSteamId::new(steam_id, steam_id, false, false))
+    }
+}
+
+/// Malformed tickets are rejected the same way as ones with a bad signature
+fn invalid_ticket(reason: &'static str) -> anyhow::Error {
+    anyhow::Error::new(SteamAuthError::InvalidTicket).context(reason)
+}
+
+impl SteamApi for DevAuthSteamApi {
+    fn verify_user_auth_ticket<'a>(
+        &'a self,
+        ticket: &'a [u8],
+    ) -> BoxFuture<'a, Result<UserSteamId, anyhow::Error>> {
+        let result = self.verify(ticket);
+        Box::pin(async move { result })
+    }
+
+    fn get_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>> {
+        let names = self.names.lock().unwrap();
+        let summaries = user_ids
+            .into_iter()
+            .filter_map(|user_id| {
+                names
+                    .get(&user_id)
+                    .map(|name| (user_id, PlayerSummary::new(user_id, name.clone())))
+            })
+            .collect();
+
+        Box::pin(async move { Ok(summaries) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn sign(payload: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        format!("{}:{}", payload, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn assert_invalid(api: &DevAuthSteamApi, ticket: impl AsRef<[u8]>) {
+        let ticket = ticket.as_ref();
+        let error = api.verify(ticket).unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<SteamAuthError>(),
+                Some(SteamAuthError::InvalidTicket)
+            ),
+            "{:?} should be an invalid ticket, got {:#}",
+            ticket,
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_ticket_verifies() {
+        let api = DevAuthSteamApi::new(SECRET.to_string());
+
+        let ids = api.verify(sign("7656:Alice").as_bytes()).unwrap();
+
+        assert_eq!(ids.steam_id, 7656);
+        assert_eq!(ids.owner_steam_id, 7656);
+        let summaries = api.get_player_summaries(vec![7656]).await.unwrap();
+        assert_eq!(summaries[&7656].name, "Alice");
+    }
+
+    #[test]
+    fn tampered_tickets_are_rejected() {
+        let api = DevAuthSteamApi::new(SECRET.to_string());
+        let signature = sign("7656:Alice").rsplit_once(':').unwrap().1.to_string();
+
+        assert_invalid(&api, format!("7657:Alice:{}", signature));
+        assert_invalid(&api, format!("7656:Mallory:{}", signature));
+        assert!(api.names.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_secrets_are_rejected() {
+        let api = DevAuthSteamApi::new("other secret".to_string());
+
+        assert_invalid(&api, sign("7656:Alice"));
+    }
+
+    #[tokio::test]
+    async fn name_with_colons_round_trips() {
+        let api = DevAuthSteamApi::new(SECRET.to_string());
+
+        let ids = api
+            .verify(sign("7656:Alice: the: Great").as_bytes())
+            .unwrap();
+
+        assert_eq!(ids.steam_id, 7656);
+        let summaries = api.get_player_summaries(vec![7656]).await.unwrap();
+        assert_eq!(summaries[&7656].name, "Alice: the: Great");
+    }
+
+    #[test]
+    fn malformed_tickets_are_rejected() {
+        let api = DevAuthSteamApi::new(SECRET.to_string());
+
+        assert_invalid(&api, "7656:Alice:not hex");
+        assert_invalid(&api, "7656");
+        assert_invalid(&api, "7656:signature");
+        assert_invalid(&api, sign("Alice:Alice"));
+        assert_invalid(&api, [0xff, 0xfe]);
+    }
+}
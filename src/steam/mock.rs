@@ -0,0 +1,101 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+use super::{PlayerSummary, SteamApi, SteamAuthError, UserSteamId};
+
+/// A user the mock api knows about, loaded from a json file
+#[derive(Debug, Deserialize)]
+pub struct MockUser {
+    /// The auth session ticket as a hex string, the same way it's sent to the real api
+    pub ticket: String,
+    pub steam_id: u64,
+    /// Defaults to `steam_id`. Set it to simulate a family shared game
+    pub owner_steam_id: Option<u64>,
+    pub name: String,
+    #[serde(default)]
+    pub vac_banned: bool,
+    #[serde(default)]
+    pub publisher_banned: bool,
+}
+
+#[cfg(test)]
+impl MockUser {
+    pub fn new(ticket: &str, steam_id: u64, name: &str) -> Self {
+        Self {
+            ticket: ticket.to_string(),
+            steam_id,
+            owner_steam_id: None,
+            name: name.to_string(),
+            vac_banned: false,
+            publisher_banned: false,
+        }
+    }
+}
+
+/// Answers requests from a fixed set of users without talking to Steam, for integration tests and local development
+pub struct MockSteamApi {
+    users: Vec<MockUser>,
+}
+
+impl MockSteamApi {
+    pub fn new(users: Vec<MockUser>) -> Self {
+        Self { users }
+    }
+
+    /// Loads the users from a json file containing an array of users
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let users: Vec<MockUser> = serde_json::from_str(&file)
+            .with_context(|| format!("Could not parse mock steam users in {}", path.display()))?;
+
+        Ok(Self::new(users))
+    }
+}
+
+impl SteamApi for MockSteamApi {
+    fn verify_user_auth_ticket<'a>(
+        &'a self,
+        ticket: &'a [u8],
+    ) -> BoxFuture<'a, Result<UserSteamId, anyhow::Error>> {
+        let ticket_str = hex::encode(ticket);
+        let result = match self
+            .users
+            .iter()
+            .find(|user| user.ticket.eq_ignore_ascii_case(&ticket_str))
+        {
+            Some(user) => Ok(UserSteamId::new(
+                user.steam_id,
+                user.owner_steam_id.unwrap_or(user.steam_id),
+                user.vac_banned,
+                user.publisher_banned,
+            )),
+            None => Err(anyhow::Error::new(SteamAuthError::InvalidTicket)
+                .context(format!("Unknown mock ticket {}", ticket_str))),
+        };
+
+        Box::pin(async move { result })
+    }
+
+    fn get_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>> {
+        let summaries = self
+            .users
+            .iter()
+            .filter(|user| user_ids.contains(&user.steam_id))
+            .map(|user| {
+                (
+                    user.steam_id,
+                    PlayerSummary::new(user.steam_id, user.name.clone()),
+                )
+            })
+            .collect();
+
+        Box::pin(async move { Ok(summaries) })
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+
+mod circuit_breaker;
+#[cfg(feature = "dev-auth")]
+mod dev_auth;
+mod mock;
+mod web;
+
+#[cfg(feature = "dev-auth")]
+pub use dev_auth::DevAuthSteamApi;
+pub use mock::MockSteamApi;
+#[cfg(test)]
+pub use mock::MockUser;
+pub use web::WebSteamApi;
+
+/// The parts of the Steam Web API the server depends on.
+/// Implemented by the real api as well as a mock for testing and local development.
+pub trait SteamApi: Send + Sync {
+    /// Verifies the user auth ticket and if successful returns the user steam id and owner id (owner id is different if the game is family shared)
+    fn verify_user_auth_ticket<'a>(
+        &'a self,
+        ticket: &'a [u8],
+    ) -> BoxFuture<'a, Result<UserSteamId, anyhow::Error>>;
+
+    fn get_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>>;
+
+    /// Like `get_player_summaries`, but always asks steam and caches the fresh summaries, so changes show up right away
+    fn refresh_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>> {
+        self.get_player_summaries(user_ids)
+    }
+}
+
+#[derive(Debug)]
+pub struct UserSteamId {
+    pub steam_id: u64,
+    pub owner_steam_id: u64,
+    pub vac_banned: bool,
+    pub publisher_banned: bool,
+}
+
+impl Display for UserSteamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "(SteamId: {}, OwnerId: {})",
+            &self.steam_id, &self.owner_steam_id
+        )
+    }
+}
+
+impl UserSteamId {
+    pub fn new(
+        steam_id: u64,
+        owner_steam_id: u64,
+        vac_banned: bool,
+        publisher_banned: bool,
+    ) -> Self {
+        Self {
+            steam_id,
+            owner_steam_id,
+            vac_banned,
+            publisher_banned,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PlayerSummary {
+    pub steam_id: u64,
+    pub name: String,
+}
+
+impl PlayerSummary {
+    pub fn new(steam_id: u64, name: String) -> Self {
+        Self { steam_id, name }
+    }
+}
+
+/// Steam could not be reached or keeps failing. Calls should be retried later rather than right away.
+#[derive(Debug)]
+pub struct SteamUnavailable {
+    pub retry_after: Duration,
+}
+
+impl Display for SteamUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Steam api is unavailable, retry after {:?}",
+            self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for SteamUnavailable {}
+
+/// Steam looked at the auth ticket and refused it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SteamAuthError {
+    /// The ticket is malformed or was never issued. Restarting steam gets the player a new one
+    InvalidTicket,
+    /// The ticket was valid once, but has expired or been cancelled
+    ExpiredTicket,
+    /// The ticket was issued for a different app than the one the server verifies against
+    WrongApp,
+    /// Steam did not accept the server's api key, so nobody can be verified until it's fixed
+    ApiKey,
+}
+
+impl SteamAuthError {
+    /// Maps the error codes AuthenticateUserTicket responds with
+    pub fn from_error_code(code: i32) -> Option<Self> {
+        match code {
+            100 | 101 => Some(SteamAuthError::InvalidTicket),
+            102 => Some(SteamAuthError::WrongApp),
+            103 => Some(SteamAuthError::ExpiredTicket),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SteamAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            SteamAuthError::InvalidTicket => "Auth ticket is invalid",
+            SteamAuthError::ExpiredTicket => "Auth ticket has expired",
+            SteamAuthError::WrongApp => "Auth ticket is for another app",
+            SteamAuthError::ApiKey => "Steam api key was not accepted",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for SteamAuthError {}
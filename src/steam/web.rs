@@ -0,0 +1,532 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use rand::Rng;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{self, Deserialize};
+
+use super::{
+    circuit_breaker::CircuitBreaker, PlayerSummary, SteamApi, SteamAuthError, SteamUnavailable,
+    UserSteamId,
+};
+
+const PATH_AUTH_USER_TICKET: &str = "/ISteamUserAuth/AuthenticateUserTicket/v1/";
+const PATH_GET_PLAYER_SUMMARIES: &str = "/ISteamUser/GetPlayerSummaries/v2/";
+
+/// The maximum number of steam ids GetPlayerSummaries accepts per request
+const MAX_SUMMARIES_PER_REQUEST: usize = 100;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times a request is sent before giving up, including the first attempt
+const MAX_ATTEMPTS: u32 = 3;
+/// The backoff before the first retry. Doubles for every retry after that, and a random part of it is actually waited
+const BASE_BACKOFF: Duration = Duration::from_millis(250);
+/// How many calls in a row have to fail before the circuit breaker stops calling steam
+const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const BREAKER_OPEN_DURATION: Duration = Duration::from_secs(30);
+/// The shortest time clients are told to wait before retrying when steam is unavailable
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct ApiResponse<T> {
+    response: Response<T>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Response<T> {
+    Ok(T),
+    Error { error: ErrorResponse },
+}
+
+#[derive(Debug, Deserialize)]
+struct ParamsResponse<T> {
+    params: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayersResponse {
+    players: Vec<ReqPlayerSummary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    #[serde(rename = "errorcode")]
+    code: i32,
+    #[serde(rename = "errordesc")]
+    description: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthenticateUserTicketParams {
+    result: String,
+    #[serde(rename = "steamid")]
+    steam_id: String,
+    #[serde(rename = "ownersteamid")]
+    owner_steam_id: String,
+    #[serde(rename = "vacbanned")]
+    vac_banned: bool,
+    #[serde(rename = "publisherbanned")]
+    publisher_banned: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReqPlayerSummary {
+    #[serde(rename = "steamid")]
+    steam_id: String,
+    #[serde(rename = "personaname")]
+    name: String,
+}
+
+/// Talks to the real Steam Web API
+pub struct WebSteamApi {
+    api_key: String,
+    app_id: u32,
+    url_auth_user_ticket: String,
+    url_get_player_summaries: String,
+    /// Shared by all requests so connections to the api are pooled
+    client: reqwest::Client,
+    summary_cache: Mutex<HashMap<u64, CachedSummary>>,
+    summary_cache_ttl: Duration,
+    circuit_breaker: CircuitBreaker,
+}
+
+struct CachedSummary {
+    summary: PlayerSummary,
+    fetched_at: Instant,
+}
+
+impl WebSteamApi {
+    /// Creates the api for the given app. `base_url` is where the api is hosted, normally https://api.steampowered.com
+    pub fn new(
+        api_key: String,
+        base_url: &str,
+        app_id: u32,
+        summary_cache_ttl: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let client = reqwest::Client::builder()
+            .user_agent("JKMP_BACKEND")
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        let base_url = base_url.trim_end_matches('/');
+
+        Ok(Self {
+            api_key,
+            app_id,
+            url_auth_user_ticket: format!("{}{}", base_url, PATH_AUTH_USER_TICKET),
+            url_get_player_summaries: format!("{}{}", base_url, PATH_GET_PLAYER_SUMMARIES),
+            client,
+            summary_cache: Mutex::new(HashMap::new()),
+            summary_cache_ttl,
+            circuit_breaker: CircuitBreaker::new(BREAKER_FAILURE_THRESHOLD, BREAKER_OPEN_DURATION),
+        })
+    }
+
+    /// Creates the api using the key from the STEAM_API_KEY environment variable
+    pub fn from_env(
+        base_url: &str,
+        app_id: u32,
+        summary_cache_ttl: Duration,
+    ) -> Result<Self, anyhow::Error> {
+        let api_key = std::env::var("STEAM_API_KEY")
+            .context("Could not find STEAM_API_KEY environment variable")?;
+        Self::new(api_key, base_url, app_id, summary_cache_ttl)
+    }
+
+    fn create_request(&self, method: reqwest::Method, url: &str) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .query(&[("key", &self.api_key)])
+    }
+
+    /// Sends the request, retrying with backoff if steam times out or responds with a server error.
+    /// Fails with `SteamUnavailable` if every attempt failed or the circuit breaker is open.
+    async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<reqwest::Response, anyhow::Error> {
+        self.circuit_breaker.check()?;
+
+        let mut attempt = 1;
+
+        loop {
+            let result = request().send().await;
+
+            let transient_error = match &result {
+                Ok(response) => {
+                    response.status().is_server_error()
+                        || response.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(error) => error.is_timeout() || error.is_connect() || error.is_request(),
+            };
+
+            if !transient_error {
+                match result {
+                    Ok(response) => {
+                        self.circuit_breaker.record_success();
+                        return Ok(response);
+                    }
+                    Err(error) => {
+                        // Not worth retrying, but the call still failed
+                        self.circuit_breaker.record_failure();
+                        return Err(error.into());
+                    }
+                }
+            }
+
+            match &result {
+                Ok(response) => tracing::warn!(
+                    "Steam api responded with {} (attempt {} of {})",
+                    response.status(),
+                    attempt,
+                    MAX_ATTEMPTS
+                ),
+                Err(error) => tracing::warn!(
+                    "Steam api request failed: {} (attempt {} of {})",
+                    error,
+                    attempt,
+                    MAX_ATTEMPTS
+                ),
+            }
+
+            if attempt >= MAX_ATTEMPTS {
+                let retry_after = self.circuit_breaker.record_failure().max(MIN_RETRY_AFTER);
+                return Err(SteamUnavailable { retry_after }.into());
+            }
+
+            // Full jitter, so retries from many handshakes at once don't all hit steam at the same time
+            let backoff = BASE_BACKOFF * 2u32.pow(attempt - 1);
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..=backoff);
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+
+    /// Removes the cached summaries of the given users and returns the ones that are still fresh
+    fn take_cached_summaries(&self, user_ids: &mut Vec<u64>) -> HashMap<u64, PlayerSummary> {
+        let mut cache = self.summary_cache.lock().unwrap();
+        cache.retain(|_, cached| cached.fetched_at.elapsed() < self.summary_cache_ttl);
+
+        let mut summaries = HashMap::new();
+        user_ids.retain(|user_id| match cache.get(user_id) {
+            Some(cached) => {
+                summaries.insert(*user_id, cached.summary.clone());
+                false
+            }
+            None => true,
+        });
+
+        summaries
+    }
+
+    fn cache_summaries(&self, summaries: &HashMap<u64, PlayerSummary>) {
+        let mut cache = self.summary_cache.lock().unwrap();
+        let fetched_at = Instant::now();
+
+        for (user_id, summary) in summaries {
+            cache.insert(
+                *user_id,
+                CachedSummary {
+                    summary: summary.clone(),
+                    fetched_at,
+                },
+            );
+        }
+    }
+}
+
+impl SteamApi for WebSteamApi {
+    fn verify_user_auth_ticket<'a>(
+        &'a self,
+        ticket: &'a [u8],
+    ) -> BoxFuture<'a, Result<UserSteamId, anyhow::Error>> {
+        Box::pin(verify_user_auth_ticket(self, ticket))
+    }
+
+    fn get_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>> {
+        Box::pin(get_player_summaries(self, user_ids))
+    }
+
+    fn refresh_player_summaries(
+        &self,
+        user_ids: Vec<u64>,
+    ) -> BoxFuture<'_, Result<HashMap<u64, PlayerSummary>, anyhow::Error>> {
+        Box::pin(refresh_player_summaries(self, user_ids))
+    }
+}
+
+async fn verify_user_auth_ticket(
+    api: &WebSteamApi,
+    ticket: &[u8],
+) -> Result<UserSteamId, anyhow::Error> {
+    let ticket_str: String = hex::encode(ticket);
+
+    let response = api
+        .send(|| {
+            api.create_request(reqwest::Method::GET, &api.url_auth_user_ticket)
+                .query(&[("appid", api.app_id)])
+                .query(&[("ticket", &ticket_str)])
+        })
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let response = response
+                .json::<ApiResponse<ParamsResponse<AuthenticateUserTicketParams>>>()
+                .await
+                .context("Unexpected api response from steam api")?;
+
+            match response.response {
+                Response::Ok(data) => {
+                    let params = data.params;
+
+                    if params.result != "OK" {
+                        return Err(anyhow::Error::new(SteamAuthError::InvalidTicket).context(
+                            format!("Steam did not accept the ticket: {}", params.result),
+                        ));
+                    }
+
+                    let steam_id = params.steam_id.parse::<u64>()?;
+                    let owner_steam_id = params.owner_steam_id.parse::<u64>()?;
+                    Ok(UserSteamId::new(
+                        steam_id,
+                        owner_steam_id,
+                        params.vac_banned,
+                        params.publisher_banned,
+                    ))
+                }
+                Response::Error { error } => {
+                    let message = format!(
+                        "Steam response was not successful: {} (error code {})",
+                        error.description, error.code
+                    );
+
+                    match SteamAuthError::from_error_code(error.code) {
+                        Some(auth_error) => Err(anyhow::Error::new(auth_error).context(message)),
+                        None => Err(anyhow::anyhow!(message)),
+                    }
+                }
+            }
+        }
+        // Steam answers requests with a missing or revoked key with forbidden
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            tracing::error!("Steam rejected the api key, check STEAM_API_KEY");
+            Err(anyhow::Error::new(SteamAuthError::ApiKey)
+                .context(format!("Steam rejected the api key: {}", response.status())))
+        }
+        default => anyhow::bail!("Unexpected response: {}", default),
+    }
+}
+
+/// Returns the summaries of the users, only asking steam for the ones that aren't cached
+async fn get_player_summaries(
+    api: &WebSteamApi,
+    mut user_ids: Vec<u64>,
+) -> Result<HashMap<u64, PlayerSummary>, anyhow::Error> {
+    let mut summaries = api.take_cached_summaries(&mut user_ids);
+    summaries.extend(refresh_player_summaries(api, user_ids).await?);
+
+    Ok(summaries)
+}
+
+async fn refresh_player_summaries(
+    api: &WebSteamApi,
+    mut user_ids: Vec<u64>,
+) -> Result<HashMap<u64, PlayerSummary>, anyhow::Error> {
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    let mut summaries = HashMap::new();
+
+    for chunk in user_ids.chunks(MAX_SUMMARIES_PER_REQUEST) {
+        let fetched = fetch_player_summaries(api, chunk).await?;
+        api.cache_summaries(&fetched);
+        summaries.extend(fetched);
+    }
+
+    Ok(summaries)
+}
+
+async fn fetch_player_summaries(
+    api: &WebSteamApi,
+    user_ids: &[u64],
+) -> Result<HashMap<u64, PlayerSummary>, anyhow::Error> {
+    let steam_ids = user_ids
+        .iter()
+        .map(|user_id| user_id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let response = api
+        .send(|| {
+            api.create_request(reqwest::Method::GET, &api.url_get_player_summaries)
+                .query(&[("steamids", &steam_ids)])
+        })
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => {
+            let response = response
+                .json::<ApiResponse<PlayersResponse>>()
+                .await
+                .context("Unexpected api response from steam api")?;
+
+            match response.response {
+                Response::Ok(data) => {
+                    let mut hashmap = HashMap::<u64, PlayerSummary>::new();
+
+                    for player in data.players {
+                        let steam_id = player.steam_id.parse::<u64>()?;
+
+                        hashmap.insert(steam_id, PlayerSummary::new(steam_id, player.name));
+                    }
+
+                    Ok(hashmap)
+                }
+                Response::Error { error } => {
+                    anyhow::bail!(
+                        "Steam response was not successful: {} (error code {})",
+                        error.description,
+                        error.code
+                    );
+                }
+            }
+        }
+        default => anyhow::bail!("Unexpected response: {}", default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const SUMMARIES_BODY: &str =
+        r#"{"response":{"players":[{"steamid":"1","personaname":"Alice"}]}}"#;
+
+    /// Serves the given statuses in order, repeating the last one, and counts the requests
+    async fn start_stub(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let index = counter.fetch_add(1, Ordering::SeqCst);
+                let status = statuses[index.min(statuses.len() - 1)];
+
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+
+                let body = if status == 200 { SUMMARIES_BODY } else { "" };
+                let response = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn create_api(url: &str) -> WebSteamApi {
+        // No cache, so every call reaches the stub
+        WebSteamApi::new("key".to_string(), url, 1, Duration::ZERO).unwrap()
+    }
+
+    async fn open_breaker(api: &WebSteamApi) {
+        for _ in 0..BREAKER_FAILURE_THRESHOLD {
+            let error = api.get_player_summaries(vec![1]).await.unwrap_err();
+            assert!(error.downcast_ref::<SteamUnavailable>().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, requests) = start_stub(vec![503, 200]).await;
+        let api = create_api(&url);
+
+        let summaries = api.get_player_summaries(vec![1]).await.unwrap();
+
+        assert_eq!(summaries[&1].name, "Alice");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_skips_the_cache_and_updates_it() {
+        let (url, requests) = start_stub(vec![200]).await;
+        let api = WebSteamApi::new("key".to_string(), &url, 1, Duration::from_secs(300)).unwrap();
+
+        api.get_player_summaries(vec![1]).await.unwrap();
+        api.get_player_summaries(vec![1]).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let summaries = api.refresh_player_summaries(vec![1]).await.unwrap();
+        assert_eq!(summaries[&1].name, "Alice");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        api.get_player_summaries(vec![1]).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn repeated_server_errors_open_the_breaker() {
+        let (url, requests) = start_stub(vec![500]).await;
+        let api = create_api(&url);
+
+        open_breaker(&api).await;
+
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            (BREAKER_FAILURE_THRESHOLD * MAX_ATTEMPTS) as usize
+        );
+        assert!(api.circuit_breaker.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn open_breaker_fails_fast() {
+        let (url, requests) = start_stub(vec![500]).await;
+        let api = create_api(&url);
+        open_breaker(&api).await;
+        let requests_before = requests.load(Ordering::SeqCst);
+
+        let started = Instant::now();
+        let error = api.get_player_summaries(vec![1]).await.unwrap_err();
+
+        assert!(error.downcast_ref::<SteamUnavailable>().is_some());
+        assert!(started.elapsed() < BASE_BACKOFF);
+        assert_eq!(requests.load(Ordering::SeqCst), requests_before);
+    }
+}
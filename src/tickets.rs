@@ -0,0 +1,48 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Auth tickets seen recently, so a captured ticket can't be replayed while steam still accepts it
+pub struct UsedTickets {
+    window: Duration,
+    tickets: HashMap<[u8; 32], UsedTicket>,
+}
+
+struct UsedTicket {
+    address: SocketAddr,
+    used_at: Instant,
+}
+
+impl UsedTickets {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            tickets: HashMap::new(),
+        }
+    }
+
+    /// Remembers that the ticket was used from the address.
+    /// Returns the address it was first used from if that was another host. Reconnecting from the same host is fine.
+    pub fn record(&mut self, ticket: &[u8], address: &SocketAddr) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let window = self.window;
+        self.tickets
+            .retain(|_, used| now.duration_since(used.used_at) < window);
+
+        let hash: [u8; 32] = Sha256::digest(ticket).into();
+        let used = self.tickets.entry(hash).or_insert(UsedTicket {
+            address: *address,
+            used_at: now,
+        });
+
+        if used.address.ip() != address.ip() {
+            Some(used.address)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::Context;
+use rustls_pemfile::Item;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+/// Creates an acceptor that terminates tls using the PEM encoded certificate chain and private key
+pub fn create_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, anyhow::Error> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid tls certificate or private key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, anyhow::Error> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+    );
+
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, anyhow::Error> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+    );
+
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+
+    anyhow::bail!("No private key found in {}", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, io::Write, sync::Arc};
+
+    use futures::{SinkExt, StreamExt};
+    use structopt::StructOpt;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio_rustls::{
+        rustls::{ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use crate::{
+        codec::MessagesCodec,
+        handlers::handshake,
+        math::Vector2,
+        messages::{HandshakeRequest, Message},
+        options::LaunchOptions,
+        protocol,
+        state::State,
+        steam::MockUser,
+    };
+
+    #[tokio::test]
+    async fn handshake_over_self_signed_tls() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let mut cert_file = tempfile::NamedTempFile::new().unwrap();
+        cert_file
+            .write_all(certificate.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let mut key_file = tempfile::NamedTempFile::new().unwrap();
+        key_file
+            .write_all(certificate.serialize_private_key_pem().as_bytes())
+            .unwrap();
+
+        let acceptor = create_acceptor(cert_file.path(), key_file.path()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(certificate.serialize_der().unwrap()))
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let state = Arc::new(Mutex::new(State::for_tests(vec![MockUser::new(
+            "0102", 7656, "Alice",
+        )])));
+        let options = LaunchOptions::from_iter(&["test", "--port", "0"]);
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+
+        let server = async {
+            let socket = acceptor.accept(server_io).await.unwrap();
+            let mut messages = MessagesCodec::new().framed(socket);
+
+            let request = match messages.next().await {
+                Some(Ok(Message::HandshakeRequest(request))) => request,
+                other => panic!("Expected a handshake request, got {:?}", other),
+            };
+
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let address = "127.0.0.1:1234".parse().unwrap();
+            handshake::handle_message(&request, tx, &mut messages, &address, &state, &options)
+                .await
+                .unwrap();
+        };
+
+        let client = async {
+            let domain = ServerName::try_from("localhost").unwrap();
+            let socket = connector.connect(domain, client_io).await.unwrap();
+            let mut messages = MessagesCodec::new().framed(socket);
+
+            messages
+                .send(Message::HandshakeRequest(HandshakeRequest {
+                    auth_session_ticket: vec![1, 2],
+                    matchmaking_password: None,
+                    level_name: "test".to_string(),
+                    position: Vector2 { x: 0.0, y: 0.0 },
+                    version: protocol::VERSION,
+                    max_peers: None,
+                }))
+                .await
+                .unwrap();
+
+            match messages.next().await {
+                Some(Ok(Message::HandshakeResponse(response))) => {
+                    assert!(response.success, "{:?}", response.error_message);
+                    assert_eq!(response.negotiated_version, protocol::VERSION);
+                }
+                other => panic!("Expected a handshake response, got {:?}", other),
+            }
+        };
+
+        tokio::join!(server, client);
+    }
+}
@@ -0,0 +1,31 @@
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::{codec::MessagesCodec, messages::Message};
+
+pub mod websocket;
+
+/// A connection to a single client that messages are read from and sent to.
+/// Handlers only depend on this, so they can run on top of any stream, including in-memory ones.
+pub trait Transport:
+    Sink<Message, Error = anyhow::Error> + Stream<Item = Result<Message, anyhow::Error>> + Unpin + Send
+{
+    /// The protocol version messages are currently encoded and decoded with
+    fn protocol_version(&self) -> u32;
+
+    fn set_protocol_version(&mut self, version: u32) -> Result<(), anyhow::Error>;
+}
+
+impl<T> Transport for Framed<T, MessagesCodec>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    fn protocol_version(&self) -> u32 {
+        self.codec().protocol_version()
+    }
+
+    fn set_protocol_version(&mut self, version: u32) -> Result<(), anyhow::Error> {
+        self.codec_mut().set_protocol_version(version)
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{
+    tungstenite::{self, protocol::WebSocketConfig},
+    WebSocketStream,
+};
+
+use super::Transport;
+use crate::{
+    codec::{MessagesCodec, MAX_FRAME_LENGTH},
+    messages::Message,
+};
+
+/// How messages are encoded in websocket frames. Replies use the format of the last frame the client sent.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// Binary frames containing the same bincode payload as the tcp protocol, minus the length prefix
+    Bincode,
+    /// Text frames containing the message as json, always in the newest protocol version's shape.
+    /// Steam ids are strings, since they don't fit in a javascript number
+    Json,
+}
+
+/// Carries messages over a websocket connection, for clients that can't open raw tcp sockets such as browsers.
+pub struct WebSocketTransport<S> {
+    inner: WebSocketStream<S>,
+    codec: MessagesCodec,
+    format: Format,
+}
+
+impl<S> WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the websocket handshake on the stream
+    pub async fn accept(stream: S) -> Result<Self, anyhow::Error> {
+        let config = WebSocketConfig {
+            max_message_size: Some(MAX_FRAME_LENGTH),
+            max_frame_size: Some(MAX_FRAME_LENGTH),
+            ..Default::default()
+        };
+
+        let inner = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+
+        Ok(Self {
+            inner,
+            codec: MessagesCodec::new(),
+            format: Format::Bincode,
+        })
+    }
+}
+
+impl<S> Stream for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message, anyhow::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let frame = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(error)) => return Poll::Ready(Some(Err(error.into()))),
+                None => return Poll::Ready(None),
+            };
+
+            let message = match frame {
+                tungstenite::Message::Binary(payload) => {
+                    self.format = Format::Bincode;
+                    self.codec.deserialize_payload(&payload)
+                }
+                tungstenite::Message::Text(text) => {
+                    self.format = Format::Json;
+                    serde_json::from_str(&text).map_err(Into::into)
+                }
+                tungstenite::Message::Close(_) => return Poll::Ready(None),
+                // Control frames are answered by tungstenite itself
+                _ => continue,
+            };
+
+            return Poll::Ready(Some(message));
+        }
+    }
+}
+
+impl<S> Sink<Message> for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let frame = match self.format {
+            Format::Json => tungstenite::Message::Text(serde_json::to_string(&item)?),
+            Format::Bincode => match self.codec.serialize_payload(item)? {
+                Some(payload) => tungstenite::Message::Binary(payload),
+                None => return Ok(()), // The message doesn't exist in the negotiated protocol version
+            },
+        };
+
+        Pin::new(&mut self.inner)
+            .start_send(frame)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+    }
+}
+
+impl<S> Transport for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    fn protocol_version(&self) -> u32 {
+        self.codec.protocol_version()
+    }
+
+    fn set_protocol_version(&mut self, version: u32) -> Result<(), anyhow::Error> {
+        self.codec.set_protocol_version(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::messages::{NearbyClientsAdded, Pong};
+
+    const STEAM_ID: u64 = 76561198000000001;
+
+    /// Has the client send `request`, answers it with a `NearbyClientsAdded` and returns the frame the client got
+    async fn exchange(request: tungstenite::Message) -> tungstenite::Message {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+
+        let server = async {
+            let mut transport = WebSocketTransport::accept(server_io).await.unwrap();
+
+            match transport.next().await {
+                Some(Ok(Message::Pong(pong))) => assert_eq!(pong.sequence, 7),
+                other => panic!("Expected a pong, got {:?}", other),
+            }
+
+            transport
+                .send(Message::NearbyClientsAdded(NearbyClientsAdded {
+                    client_ids: vec![STEAM_ID],
+                }))
+                .await
+                .unwrap();
+        };
+
+        let client = async {
+            let (mut socket, _) = tokio_tungstenite::client_async("ws://localhost/", client_io)
+                .await
+                .unwrap();
+            socket.send(request).await.unwrap();
+            next_data_frame(&mut socket).await
+        };
+
+        tokio::join!(server, client).1
+    }
+
+    async fn next_data_frame(socket: &mut WebSocketStream<DuplexStream>) -> tungstenite::Message {
+        loop {
+            match socket.next().await {
+                Some(Ok(frame)) if frame.is_binary() || frame.is_text() => return frame,
+                Some(Ok(_)) => continue,
+                other => panic!("Expected a data frame, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bincode_frames() {
+        let codec = MessagesCodec::new();
+        let request = codec
+            .serialize_payload(Message::Pong(Pong { sequence: 7 }))
+            .unwrap()
+            .unwrap();
+
+        let payload = match exchange(tungstenite::Message::Binary(request)).await {
+            tungstenite::Message::Binary(payload) => payload,
+            other => panic!("Expected a binary frame, got {:?}", other),
+        };
+
+        match codec.deserialize_payload(&payload).unwrap() {
+            Message::NearbyClientsAdded(message) => assert_eq!(message.client_ids, [STEAM_ID]),
+            other => panic!("Expected NearbyClientsAdded, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn json_frames_with_string_steam_ids() {
+        let request = tungstenite::Message::Text(r#"{"Pong":{"sequence":7}}"#.to_string());
+
+        let text = match exchange(request).await {
+            tungstenite::Message::Text(text) => text,
+            other => panic!("Expected a text frame, got {:?}", other),
+        };
+
+        assert_eq!(
+            text,
+            r#"{"NearbyClientsAdded":{"client_ids":["76561198000000001"]}}"#
+        );
+    }
+}
@@ -1,2 +1,3 @@
 pub mod networking;
+pub mod steam_id;
 pub mod string;
@@ -1,16 +1,17 @@
 use futures::SinkExt;
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
 
 use crate::{
     client::Client,
-    codec::MessagesCodec,
     messages::{InformNearbyClients, Message},
+    transport::Transport,
 };
 
+/// Keeps messages listing client ids well below the maximum frame length
+pub const MAX_CLIENT_IDS_PER_MESSAGE: usize = 50;
+
 pub async fn send_nearby_clients(
     except_steam_id: &u64,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut impl Transport,
     clients: &[&Client],
 ) -> Result<(), anyhow::Error> {
     let nearby_client_ids: Vec<u64> = clients
@@ -20,7 +21,7 @@ pub async fn send_nearby_clients(
         .collect();
 
     // Split up message into multiple messages if there's more than 50 clients to send
-    for chunk in nearby_client_ids.chunks(50) {
+    for chunk in nearby_client_ids.chunks(MAX_CLIENT_IDS_PER_MESSAGE) {
         messages
             .send(Message::InformNearbyClients(InformNearbyClients {
                 client_ids: chunk.into(),
@@ -0,0 +1,145 @@
+//! Serializes steam ids as strings in human readable formats such as json, and as plain numbers otherwise.
+//! Steam ids don't fit in the 53 bits javascript numbers can hold exactly, so browsers would read them wrong.
+//! Use the modules with `#[serde(with = "...")]` on `u64`, `Option<u64>` and `Vec<u64>` fields.
+
+use std::fmt;
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+struct SteamId(u64);
+
+impl Serialize for SteamId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(&self.0)
+        } else {
+            serializer.serialize_u64(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SteamId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SteamIdVisitor;
+
+        impl<'de> Visitor<'de> for SteamIdVisitor {
+            type Value = SteamId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a steam id as a number or a string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<SteamId, E> {
+                Ok(SteamId(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<SteamId, E> {
+                value.parse().map(SteamId).map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(SteamIdVisitor)
+        } else {
+            deserializer.deserialize_u64(SteamIdVisitor)
+        }
+    }
+}
+
+pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    SteamId(*id).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    SteamId::deserialize(deserializer).map(|id| id.0)
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        id.map(SteamId).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<SteamId>::deserialize(deserializer).map(|id| id.map(|id| id.0))
+    }
+}
+
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ids: &[u64], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(ids.iter().copied().map(SteamId))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+        Vec::<SteamId>::deserialize(deserializer)
+            .map(|ids| ids.into_iter().map(|id| id.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+
+    use crate::{
+        chat::ChatChannel,
+        codec::MessagesCodec,
+        messages::{Message, OutgoingChatMessage, PlayerNameChanged},
+    };
+
+    const STEAM_ID: u64 = 76561198000000001;
+
+    #[test]
+    fn bincode_keeps_numbers() {
+        let options = bincode::DefaultOptions::new()
+            .with_little_endian()
+            .with_varint_encoding();
+        let name_changed = PlayerNameChanged {
+            steam_id: STEAM_ID,
+            name: "Alice".to_string(),
+        };
+
+        assert_eq!(
+            options.serialize(&name_changed).unwrap(),
+            options.serialize(&(STEAM_ID, "Alice")).unwrap()
+        );
+        let payload = MessagesCodec::new()
+            .serialize_payload(Message::PlayerNameChanged(name_changed))
+            .unwrap()
+            .unwrap();
+        match MessagesCodec::new().deserialize_payload(&payload).unwrap() {
+            Message::PlayerNameChanged(message) => assert_eq!(message.steam_id, STEAM_ID),
+            other => panic!("Expected PlayerNameChanged, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_uses_strings() {
+        let message = OutgoingChatMessage {
+            channel: ChatChannel::Global,
+            sender_id: Some(STEAM_ID),
+            sender_name: None,
+            message: "hi".to_string(),
+        };
+
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["sender_id"], "76561198000000001");
+
+        let message: OutgoingChatMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(message.sender_id, Some(STEAM_ID));
+    }
+
+    #[test]
+    fn json_accepts_numbers() {
+        let message: PlayerNameChanged =
+            serde_json::from_str(r#"{"steam_id": 7656, "name": "Alice"}"#).unwrap();
+        assert_eq!(message.steam_id, 7656);
+    }
+}